@@ -1,3 +1,7 @@
+import 'dart:convert';
+import 'dart:math';
+import 'dart:typed_data';
+
 import 'package:collection/collection.dart';
 import 'package:fluffychat/config/app_config.dart';
 import 'package:fluffychat/config/setting_keys.dart';
@@ -25,6 +29,15 @@ void main() async {
   // To make sure that the parts of flutter needed are started up already, we need to ensure that the
   // widget bindings are initialized already.
   WidgetsFlutterBinding.ensureInitialized();
+  MatrixInitFailed.rustSignalStream.first.then(
+    (signal) => Logs().e('The hub failed to start: ${signal.message.error}'),
+  );
+  InitMatrix(
+    dataDirectory: (await getApplicationSupportDirectory()).path,
+    // Workaround for secure storage is calling Platform.operatingSystem on web
+    storeKey: PlatformInfos.isWeb ? Uint8List(0) : await _hubStoreKey(),
+    noSecureStorage: PlatformInfos.isWeb,
+  ).sendSignalToRust();
   MediaKit.ensureInitialized();
   GoRouter.optionURLReflectsImperativeAPIs = true;
   if (PlatformInfos.isLinux) {
@@ -70,6 +83,32 @@ void main() async {
   await startGui(clients);
 }
 
+const _hubStoreKeyName = 'hub.store_key';
+
+/// The key the hub encrypts the sessions and stores of the accounts with,
+/// generated on first start and kept in the secure storage. Empty if it
+/// can't be read or kept, the hub then refuses to start.
+Future<Uint8List> _hubStoreKey() async {
+  try {
+    const secureStorage = FlutterSecureStorage();
+    final storedKey = await secureStorage.read(key: _hubStoreKeyName);
+    if (storedKey != null) return base64Url.decode(storedKey);
+
+    final random = Random.secure();
+    final key = Uint8List.fromList(
+      List.generate(32, (_) => random.nextInt(256)),
+    );
+    await secureStorage.write(
+      key: _hubStoreKeyName,
+      value: base64UrlEncode(key),
+    );
+    return key;
+  } catch (e, s) {
+    Logs().e('Unable to keep the hub store key', e, s);
+    return Uint8List(0);
+  }
+}
+
 /// Fetch the pincode for the applock and start the flutter engine.
 Future<void> startGui(List<Client> clients) async {
   // Fetch the pin for the applock if existing for mobile applications.
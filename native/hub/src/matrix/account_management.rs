@@ -0,0 +1,65 @@
+use rinf::debug_print;
+
+use crate::{
+    matrix::client::{AccountManagementAction, ArcMatrixClients},
+    messages::*,
+};
+
+impl AccountAction {
+    fn into_action(self, device_id: String) -> Option<AccountManagementAction> {
+        match self {
+            AccountAction::Unspecified => None,
+            AccountAction::Profile => Some(AccountManagementAction::Profile),
+            AccountAction::SessionsList => Some(AccountManagementAction::SessionsList),
+            AccountAction::SessionView => Some(AccountManagementAction::SessionView { device_id }),
+            AccountAction::SessionEnd => Some(AccountManagementAction::SessionEnd { device_id }),
+            AccountAction::AccountDeactivate => Some(AccountManagementAction::AccountDeactivate),
+            AccountAction::CrossSigningReset => Some(AccountManagementAction::CrossSigningReset),
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    let receiver = GetAccountManagementUrl::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetAccountManagementUrl = dart_signal.message;
+        debug_print!("GetAccountManagementUrl: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let Some(client) = client else {
+            debug_print!(
+                "GetAccountManagementUrl: no client with associated {} was found",
+                &message.id
+            );
+            AccountManagementUrl {
+                id: message.id,
+                action: message.action,
+                url: Default::default(),
+                error: "missing client".to_string(),
+            }
+            .send_signal_to_dart();
+            continue;
+        };
+
+        let action = message.action().into_action(message.device_id);
+        match client.account_url(action).await {
+            Ok(url) => AccountManagementUrl {
+                id: message.id,
+                action: message.action,
+                url: url.map(String::from).unwrap_or_default(),
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("GetAccountManagementUrl: err {err:?}");
+                AccountManagementUrl {
+                    id: message.id,
+                    action: message.action,
+                    url: Default::default(),
+                    error: err.to_string(),
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
@@ -2,12 +2,13 @@ use matrix_sdk::{
     authentication::oauth::{
         error::OAuthAuthorizationCodeError,
         registration::{ApplicationType, ClientMetadata, Localized, OAuthGrantType},
-        ClientId, ClientRegistrationData, OAuthAuthorizationData, OAuthError as SdkOAuthError,
+        AccountManagementActionFull, ClientId, ClientRegistrationData, OAuthAuthorizationData,
+        OAuthError as SdkOAuthError,
     },
     ruma::OwnedDeviceId,
-    Error,
+    AuthApi, Error,
 };
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use matrix_sdk::{
     ruma::{
@@ -25,7 +26,12 @@ use url::Url;
 
 pub type ArcMatrixClients = Arc<Mutex<HashMap<String, MatrixClient>>>;
 
-pub struct MatrixClient(pub Client);
+/// A client, along with the directory of its persistent stores.
+///
+/// Clients that only probe a homeserver keep everything in memory and have
+/// no store directory.
+#[derive(Clone)]
+pub struct MatrixClient(pub Client, pub Option<PathBuf>);
 
 #[derive(Debug)]
 pub enum SlidingSyncVersion {
@@ -108,6 +114,44 @@ impl From<OidcPrompt> for RumaOidcPrompt {
         }
     }
 }
+
+/// A page of the account management UI on the OAuth 2.0 authorization
+/// server, as defined in [MSC4191].
+///
+/// [MSC4191]: https://github.com/matrix-org/matrix-spec-proposals/pull/4191
+#[derive(Debug)]
+pub enum AccountManagementAction {
+    /// The user wishes to view their profile (name, avatar, contact details).
+    Profile,
+    /// The user wishes to view a list of their sessions.
+    SessionsList,
+    /// The user wishes to view the details of a specific session.
+    SessionView { device_id: String },
+    /// The user wishes to end/log out of a specific session.
+    SessionEnd { device_id: String },
+    /// The user wishes to deactivate their account.
+    AccountDeactivate,
+    /// The user wishes to reset their cross-signing keys.
+    CrossSigningReset,
+}
+
+impl From<AccountManagementAction> for AccountManagementActionFull {
+    fn from(value: AccountManagementAction) -> Self {
+        match value {
+            AccountManagementAction::Profile => Self::Profile,
+            AccountManagementAction::SessionsList => Self::SessionsList,
+            AccountManagementAction::SessionView { device_id } => Self::SessionView {
+                device_id: device_id.into(),
+            },
+            AccountManagementAction::SessionEnd { device_id } => Self::SessionEnd {
+                device_id: device_id.into(),
+            },
+            AccountManagementAction::AccountDeactivate => Self::AccountDeactivate,
+            AccountManagementAction::CrossSigningReset => Self::CrossSigningReset,
+        }
+    }
+}
+
 /// The configuration to use when authenticating with OIDC.
 pub struct OidcConfiguration {
     /// The name of the client that will be shown during OIDC authentication.
@@ -248,7 +292,40 @@ impl MatrixClient {
             .server_name_or_homeserver_url(name_or_homeserver_url)
             .build()
             .await
-            .map(Self)
+            .map(|client| Self(client, None))
+    }
+
+    /// Builds a client keeping its state and crypto stores in `store_path`,
+    /// so that they outlive the process and survive a soft logout.
+    ///
+    /// The stores are encrypted with `passphrase`, if any.
+    pub async fn with_store(
+        name_or_homeserver_url: &str,
+        store_path: PathBuf,
+        passphrase: Option<&str>,
+    ) -> Result<Self, matrix_sdk::ClientBuildError> {
+        Client::builder()
+            .server_name_or_homeserver_url(name_or_homeserver_url)
+            .sqlite_store(&store_path, passphrase)
+            .handle_refresh_tokens()
+            .build()
+            .await
+            .map(|client| Self(client, Some(store_path)))
+    }
+
+    /// Like `with_store`, for a session another process may be using: the
+    /// client never refreshes the tokens, as a refresh token is single use.
+    pub async fn with_store_without_refresh(
+        name_or_homeserver_url: &str,
+        store_path: PathBuf,
+        passphrase: Option<&str>,
+    ) -> Result<Self, matrix_sdk::ClientBuildError> {
+        Client::builder()
+            .server_name_or_homeserver_url(name_or_homeserver_url)
+            .sqlite_store(&store_path, passphrase)
+            .build()
+            .await
+            .map(|client| Self(client, Some(store_path)))
     }
 
     pub async fn homeserver_login_details(&self) -> HomeserverLoginDetails {
@@ -362,6 +439,30 @@ impl MatrixClient {
 
         Ok(())
     }
+
+    /// The URL of the account management page on the authorization server,
+    /// optionally deep linking to the page for the given action.
+    ///
+    /// Returns `None` if the session wasn't created via `url_for_oidc` or if
+    /// the server metadata doesn't advertise an account management URL.
+    pub async fn account_url(
+        &self,
+        action: Option<AccountManagementAction>,
+    ) -> Result<Option<Url>, OidcError> {
+        if !matches!(self.0.auth_api(), Some(AuthApi::OAuth(_))) {
+            return Ok(None);
+        }
+
+        let Some(mut url_builder) = self.0.oauth().account_management_url().await? else {
+            return Ok(None);
+        };
+
+        if let Some(action) = action {
+            url_builder = url_builder.action(action.into());
+        }
+
+        Ok(Some(url_builder.build()))
+    }
 }
 
 trait OptionExt {
@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use matrix_sdk::{
+    ruma::{
+        api::client::room::create_room::v3::{Request as CreateRoomRequest, RoomPreset},
+        events::{
+            room::{avatar::RoomAvatarEventContent, encryption::RoomEncryptionEventContent},
+            InitialStateEvent,
+        },
+        IdParseError, OwnedRoomId, OwnedUserId, UserId,
+    },
+    Room, RoomState,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        media,
+    },
+    messages::*,
+};
+
+/// How long to wait for a created room to come down the sync.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Media(#[from] media::Error),
+    #[error("A direct chat needs exactly one invitee.")]
+    InvalidDirectChat,
+}
+
+/// The outcome of a `CreateRoom` command.
+#[derive(Debug)]
+pub struct CreatedRoom {
+    pub room_id: OwnedRoomId,
+    /// An existing direct chat with the same user was reused.
+    pub reused: bool,
+    /// The room came down the sync before the timeout.
+    pub synced: bool,
+    /// The room was created but its avatar couldn't be set.
+    pub avatar_error: Option<Error>,
+}
+
+impl From<CreateRoomPreset> for RoomPreset {
+    fn from(value: CreateRoomPreset) -> Self {
+        match value {
+            CreateRoomPreset::PrivateChat => Self::PrivateChat,
+            CreateRoomPreset::TrustedPrivateChat => Self::TrustedPrivateChat,
+            CreateRoomPreset::PublicChat => Self::PublicChat,
+        }
+    }
+}
+
+/// Creates a direct chat with `user_id`, or returns the joined one if it
+/// already exists.
+///
+/// The room is encrypted and added to the `m.direct` account data.
+pub async fn create_direct_chat(
+    client: &MatrixClient,
+    user_id: &UserId,
+) -> Result<CreatedRoom, Error> {
+    if let Some(room) = client
+        .0
+        .get_dm_room(user_id)
+        .filter(|room| room.state() == RoomState::Joined)
+    {
+        return Ok(CreatedRoom {
+            room_id: room.room_id().to_owned(),
+            reused: true,
+            synced: true,
+            avatar_error: None,
+        });
+    }
+
+    let room = client.0.create_dm(user_id).await?;
+
+    Ok(CreatedRoom {
+        room_id: room.room_id().to_owned(),
+        reused: false,
+        synced: wait_for_sync(client, room.room_id().to_owned()).await,
+        avatar_error: None,
+    })
+}
+
+/// Uploads the avatar and sets it on the room.
+async fn set_avatar(
+    client: &MatrixClient,
+    room: &Room,
+    content_type: &str,
+    data: Vec<u8>,
+) -> Result<(), Error> {
+    let url = media::upload(client, content_type, data).await?;
+    let mut avatar = RoomAvatarEventContent::new();
+    avatar.url = Some(url);
+    room.send_state_event(avatar).await?;
+
+    Ok(())
+}
+
+pub async fn create_group_chat(
+    client: &MatrixClient,
+    message: CreateRoom,
+) -> Result<CreatedRoom, Error> {
+    let preset: RoomPreset = message.preset().into();
+    let invite = message
+        .invitees
+        .iter()
+        .map(|user_id| UserId::parse(user_id.as_str()))
+        .collect::<Result<Vec<OwnedUserId>, _>>()?;
+
+    let mut initial_state = Vec::new();
+    if !message.disable_encryption {
+        initial_state.push(
+            InitialStateEvent::new(RoomEncryptionEventContent::with_recommended_defaults())
+                .to_raw_any(),
+        );
+    }
+
+    let mut request = CreateRoomRequest::new();
+    request.name = Some(message.name).filter(|name| !name.is_empty());
+    request.topic = Some(message.topic).filter(|topic| !topic.is_empty());
+    request.invite = invite;
+    request.preset = Some(preset);
+    request.initial_state = initial_state;
+
+    let room = client.0.create_room(request).await?;
+
+    // The avatar is only uploaded once the room exists, so that a failed
+    // creation doesn't leave the media behind.
+    let avatar_error = if message.avatar.is_empty() {
+        None
+    } else {
+        set_avatar(client, &room, &message.avatar_content_type, message.avatar)
+            .await
+            .err()
+    };
+
+    Ok(CreatedRoom {
+        room_id: room.room_id().to_owned(),
+        reused: false,
+        synced: wait_for_sync(client, room.room_id().to_owned()).await,
+        avatar_error,
+    })
+}
+
+/// Waits for the room to come down the sync, so that Dart can open it right
+/// away. Returns `false` on timeout, for example if the client isn't syncing.
+async fn wait_for_sync(client: &MatrixClient, room_id: OwnedRoomId) -> bool {
+    tokio::time::timeout(SYNC_TIMEOUT, client.0.await_room_remote_echo(&room_id))
+        .await
+        .is_ok()
+}
+
+pub async fn create_room(client: &MatrixClient, message: CreateRoom) -> Result<CreatedRoom, Error> {
+    if !message.is_direct {
+        return create_group_chat(client, message).await;
+    }
+
+    match message.invitees.as_slice() {
+        [user_id] => create_direct_chat(client, &UserId::parse(user_id.as_str())?).await,
+        _ => Err(Error::InvalidDirectChat),
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    let receiver = CreateRoom::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: CreateRoom = dart_signal.message;
+        debug_print!(
+            "CreateRoom: received {} direct: {} invitees: {:?}",
+            message.id,
+            message.is_direct,
+            message.invitees
+        );
+
+        let id = message.id.clone();
+        let client = clients.lock().await.get(&id).cloned();
+        let Some(client) = client else {
+            debug_print!("CreateRoom: no client with associated {} was found", &id);
+            RoomCreated {
+                id,
+                error: "missing client".to_string(),
+                ..Default::default()
+            }
+            .send_signal_to_dart();
+            continue;
+        };
+
+        match create_room(&client, message).await {
+            Ok(created) => {
+                debug_print!("CreateRoom: ok {created:?}");
+                RoomCreated {
+                    id,
+                    room_id: created.room_id.to_string(),
+                    reused: created.reused,
+                    synced: created.synced,
+                    avatar_error: created
+                        .avatar_error
+                        .map(|err| err.to_string())
+                        .unwrap_or_default(),
+                    error: Default::default(),
+                }
+                .send_signal_to_dart();
+            }
+            Err(err) => {
+                debug_print!("CreateRoom: err {err:?}");
+                RoomCreated {
+                    id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
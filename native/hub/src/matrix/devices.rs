@@ -0,0 +1,300 @@
+use matrix_sdk::{
+    encryption::CryptoStoreError,
+    ruma::{
+        api::client::uiaa::{AuthData, AuthType, Password, UserIdentifier},
+        OwnedDeviceId,
+    },
+    AuthApi, HttpError,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::client::{AccountManagementAction, ArcMatrixClients, MatrixClient, OidcError},
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] HttpError),
+    #[error(transparent)]
+    Crypto(#[from] CryptoStoreError),
+    #[error(transparent)]
+    Oidc(#[from] OidcError),
+    #[error("The client is not logged in.")]
+    NotLoggedIn,
+}
+
+/// The result of a device deletion attempt.
+#[derive(Debug)]
+pub enum DeleteOutcome {
+    Deleted,
+    /// The homeserver asked for user-interactive auth, or the session is an
+    /// OAuth one and the devices have to be removed on the auth server.
+    AuthRequired {
+        session: Option<String>,
+        supports_password: bool,
+        account_management_url: Option<String>,
+        auth_error: Option<String>,
+    },
+}
+
+/// Lists the devices of the logged in account, most recently seen first,
+/// with the current device on top.
+pub async fn list_devices(client: &MatrixClient) -> Result<Vec<DeviceInfo>, Error> {
+    let user_id = client.0.user_id().ok_or(Error::NotLoggedIn)?.to_owned();
+    let current_device_id = client.0.device_id().map(ToOwned::to_owned);
+    let response = client.0.devices().await?;
+    let encryption = client.0.encryption();
+
+    let mut devices = Vec::with_capacity(response.devices.len());
+    for device in response.devices {
+        let crypto_device = encryption.get_device(&user_id, &device.device_id).await?;
+
+        devices.push(DeviceInfo {
+            is_current: current_device_id.as_ref() == Some(&device.device_id),
+            device_id: device.device_id.to_string(),
+            display_name: device.display_name.unwrap_or_default(),
+            last_seen_ip: device.last_seen_ip.unwrap_or_default(),
+            last_seen_ts: device
+                .last_seen_ts
+                .map(|ts| ts.0.into())
+                .unwrap_or_default(),
+            is_verified: crypto_device.as_ref().is_some_and(|d| d.is_verified()),
+            is_cross_signed_by_owner: crypto_device
+                .as_ref()
+                .is_some_and(|d| d.is_cross_signed_by_owner()),
+            is_locally_trusted: crypto_device
+                .as_ref()
+                .is_some_and(|d| d.is_locally_trusted()),
+        });
+    }
+
+    devices.sort_by(|a, b| {
+        b.is_current
+            .cmp(&a.is_current)
+            .then(b.last_seen_ts.cmp(&a.last_seen_ts))
+    });
+
+    Ok(devices)
+}
+
+pub async fn rename_device(
+    client: &MatrixClient,
+    device_id: String,
+    display_name: String,
+) -> Result<(), Error> {
+    let device_id = OwnedDeviceId::from(device_id);
+
+    client.0.rename_device(&device_id, &display_name).await?;
+
+    Ok(())
+}
+
+/// Deletes the given devices.
+///
+/// Call it without a password first. If the homeserver requires
+/// user-interactive auth, call it again with the returned session and the
+/// user's password.
+///
+/// Sessions created via `url_for_oidc` can't complete password auth, so for
+/// them no request is made and the account management URL is returned
+/// instead.
+pub async fn delete_devices(
+    client: &MatrixClient,
+    device_ids: Vec<String>,
+    password: Option<String>,
+    session: Option<String>,
+) -> Result<DeleteOutcome, Error> {
+    let device_ids: Vec<OwnedDeviceId> = device_ids.into_iter().map(Into::into).collect();
+
+    if let Some(AuthApi::OAuth(_)) = client.0.auth_api() {
+        let action = match device_ids.as_slice() {
+            [device_id] => AccountManagementAction::SessionEnd {
+                device_id: device_id.to_string(),
+            },
+            _ => AccountManagementAction::SessionsList,
+        };
+        let account_management_url = client.account_url(Some(action)).await?.map(String::from);
+
+        return Ok(DeleteOutcome::AuthRequired {
+            session: None,
+            supports_password: false,
+            account_management_url,
+            auth_error: None,
+        });
+    }
+
+    let auth_data = match password {
+        Some(password) => {
+            let user_id = client.0.user_id().ok_or(Error::NotLoggedIn)?;
+            let mut password = Password::new(
+                UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+                password,
+            );
+            password.session = session;
+            Some(AuthData::Password(password))
+        }
+        None => None,
+    };
+
+    match client.0.delete_devices(&device_ids, auth_data).await {
+        Ok(_) => Ok(DeleteOutcome::Deleted),
+        Err(err) => match err.as_uiaa_response() {
+            Some(info) => Ok(DeleteOutcome::AuthRequired {
+                session: info.session.clone(),
+                supports_password: info
+                    .flows
+                    .iter()
+                    .any(|flow| flow.stages == [AuthType::Password]),
+                account_management_url: None,
+                auth_error: info.auth_error.as_ref().map(|e| e.message.clone()),
+            }),
+            None => Err(Error::Http(err)),
+        },
+    }
+}
+
+async fn communicate_list(clients: ArcMatrixClients) {
+    let receiver = ListDevices::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ListDevices = dart_signal.message;
+        debug_print!("ListDevices: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let Some(client) = client else {
+            debug_print!(
+                "ListDevices: no client with associated {} was found",
+                &message.id
+            );
+            DevicesList {
+                id: message.id,
+                devices: Default::default(),
+                error: "missing client".to_string(),
+            }
+            .send_signal_to_dart();
+            continue;
+        };
+
+        match list_devices(&client).await {
+            Ok(devices) => DevicesList {
+                id: message.id,
+                devices,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("ListDevices: err {err:?}");
+                DevicesList {
+                    id: message.id,
+                    devices: Default::default(),
+                    error: err.to_string(),
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_rename(clients: ArcMatrixClients) {
+    let receiver = RenameDevice::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: RenameDevice = dart_signal.message;
+        debug_print!("RenameDevice: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let Some(client) = client else {
+            debug_print!(
+                "RenameDevice: no client with associated {} was found",
+                &message.id
+            );
+            DeviceRenamed {
+                id: message.id,
+                device_id: message.device_id,
+                error: "missing client".to_string(),
+            }
+            .send_signal_to_dart();
+            continue;
+        };
+
+        let result = rename_device(&client, message.device_id.clone(), message.display_name).await;
+        if let Err(err) = &result {
+            debug_print!("RenameDevice: err {err:?}");
+        }
+        DeviceRenamed {
+            id: message.id,
+            device_id: message.device_id,
+            error: result.err().map(|e| e.to_string()).unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+async fn communicate_delete(clients: ArcMatrixClients) {
+    let receiver = DeleteDevices::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: DeleteDevices = dart_signal.message;
+        debug_print!("DeleteDevices: received {:?}", message.device_ids);
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let Some(client) = client else {
+            debug_print!(
+                "DeleteDevices: no client with associated {} was found",
+                &message.id
+            );
+            DevicesDeleted {
+                id: message.id,
+                device_ids: message.device_ids,
+                error: "missing client".to_string(),
+                ..Default::default()
+            }
+            .send_signal_to_dart();
+            continue;
+        };
+
+        let password = Some(message.password).filter(|p| !p.is_empty());
+        let session = Some(message.uia_session).filter(|s| !s.is_empty());
+
+        match delete_devices(&client, message.device_ids.clone(), password, session).await {
+            Ok(DeleteOutcome::Deleted) => DevicesDeleted {
+                id: message.id,
+                device_ids: message.device_ids,
+                ..Default::default()
+            }
+            .send_signal_to_dart(),
+            Ok(DeleteOutcome::AuthRequired {
+                session,
+                supports_password,
+                account_management_url,
+                auth_error,
+            }) => DevicesDeleted {
+                id: message.id,
+                device_ids: message.device_ids,
+                auth_required: true,
+                uia_session: session.unwrap_or_default(),
+                password_supported: supports_password,
+                account_management_url: account_management_url.unwrap_or_default(),
+                error: auth_error.unwrap_or_default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("DeleteDevices: err {err:?}");
+                DevicesDeleted {
+                    id: message.id,
+                    device_ids: message.device_ids,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_list(clients.clone()),
+        communicate_rename(clients.clone()),
+        communicate_delete(clients),
+    );
+}
@@ -0,0 +1,223 @@
+use matrix_sdk::{
+    ruma::{EventId, IdParseError, RoomId},
+    ComposerDraft, ComposerDraftType, Room,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        room_list,
+    },
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error("A reply or an edit needs the event it targets.")]
+    MissingTarget,
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+fn room(client: &MatrixClient, room_id: &str) -> Result<Room, Error> {
+    client
+        .0
+        .get_room(&RoomId::parse(room_id)?)
+        .ok_or(Error::RoomNotFound)
+}
+
+impl RoomDraft {
+    fn from_sdk(room: &Room, draft: ComposerDraft) -> Self {
+        let (kind, target_event_id) = match draft.draft_type {
+            ComposerDraftType::NewMessage => (DraftKind::NewMessage, String::new()),
+            ComposerDraftType::Reply { event_id } => (DraftKind::Reply, event_id.to_string()),
+            ComposerDraftType::Edit { event_id } => (DraftKind::Edit, event_id.to_string()),
+        };
+        Self {
+            room_id: room.room_id().to_string(),
+            plain_text: draft.plain_text,
+            html_text: draft.html_text.unwrap_or_default(),
+            kind: kind.into(),
+            target_event_id,
+        }
+    }
+
+    fn into_sdk(self) -> Result<ComposerDraft, Error> {
+        let target = || match self.target_event_id.as_str() {
+            "" => Err(Error::MissingTarget),
+            event_id => Ok(EventId::parse(event_id)?),
+        };
+        let draft_type = match self.kind() {
+            DraftKind::NewMessage => ComposerDraftType::NewMessage,
+            DraftKind::Reply => ComposerDraftType::Reply {
+                event_id: target()?,
+            },
+            DraftKind::Edit => ComposerDraftType::Edit {
+                event_id: target()?,
+            },
+        };
+        Ok(ComposerDraft {
+            plain_text: self.plain_text,
+            html_text: Some(self.html_text).filter(|html| !html.is_empty()),
+            draft_type,
+        })
+    }
+}
+
+/// Saves the draft of a room in the store, or clears it if it's empty.
+pub async fn save_draft(room: &Room, draft: RoomDraft) -> Result<(), Error> {
+    if draft.plain_text.is_empty() {
+        room.clear_composer_draft().await?;
+    } else {
+        room.save_composer_draft(draft.into_sdk()?).await?;
+    }
+    Ok(())
+}
+
+pub async fn load_draft(room: &Room) -> Result<Option<RoomDraft>, Error> {
+    Ok(room
+        .load_composer_draft()
+        .await?
+        .map(|draft| RoomDraft::from_sdk(room, draft)))
+}
+
+/// The drafts of all the joined rooms, for the "rooms with drafts" filter.
+pub async fn list_drafts(client: &MatrixClient) -> Result<Vec<RoomDraft>, Error> {
+    let mut drafts = Vec::new();
+    for room in client.0.joined_rooms() {
+        if let Some(draft) = load_draft(&room).await? {
+            drafts.push(draft);
+        }
+    }
+    Ok(drafts)
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+/// The room list is updated too, drafts not coming from the sync.
+async fn communicate_save(clients: ArcMatrixClients) {
+    let receiver = SaveDraft::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SaveDraft = dart_signal.message;
+        debug_print!(
+            "SaveDraft: received {} room: {}",
+            message.id,
+            message.room_id
+        );
+
+        let draft = message.draft.unwrap_or_default();
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => match room(&client, &message.room_id) {
+                Ok(room) => save_draft(&room, draft).await.map(|()| room),
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+
+        let error = match result {
+            Ok(room) => {
+                room_list::send_summaries(message.id.clone(), &[room], false).await;
+                Default::default()
+            }
+            Err(err) => {
+                debug_print!("SaveDraft: err {err:?}");
+                err.to_string()
+            }
+        };
+        DraftSaved {
+            id: message.id,
+            room_id: message.room_id,
+            error,
+        }
+        .send_signal_to_dart();
+    }
+}
+
+async fn communicate_load(clients: ArcMatrixClients) {
+    let receiver = LoadDraft::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: LoadDraft = dart_signal.message;
+        debug_print!("LoadDraft: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => match room(&client, &message.room_id) {
+                Ok(room) => load_draft(&room).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(draft) => DraftLoaded {
+                id: message.id,
+                room_id: message.room_id,
+                draft,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("LoadDraft: err {err:?}");
+                DraftLoaded {
+                    id: message.id,
+                    room_id: message.room_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_list(clients: ArcMatrixClients) {
+    let receiver = ListDrafts::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ListDrafts = dart_signal.message;
+        debug_print!("ListDrafts: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => list_drafts(&client).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(drafts) => DraftsList {
+                id: message.id,
+                drafts,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("ListDrafts: err {err:?}");
+                DraftsList {
+                    id: message.id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_save(clients.clone()),
+        communicate_load(clients.clone()),
+        communicate_list(clients),
+    );
+}
@@ -0,0 +1,374 @@
+use std::io::Cursor;
+
+use matrix_sdk::{
+    media::{MediaFormat, MediaRequestParameters},
+    room::{IncludeRelations, RelationsOptions},
+    ruma::{
+        api::Direction,
+        events::{
+            relation::RelationType,
+            room::{
+                message::{
+                    MessageType, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent,
+                },
+                MediaSource,
+            },
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+        },
+        EventId, IdParseError, RoomId,
+    },
+    Room,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        media,
+    },
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Media(#[from] media::Error),
+    #[error("Only messages can be forwarded.")]
+    Unsupported,
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+fn room(client: &MatrixClient, room_id: &str) -> Result<Room, Error> {
+    client
+        .0
+        .get_room(&RoomId::parse(room_id)?)
+        .ok_or(Error::RoomNotFound)
+}
+
+/// Removes the quoted lines a reply starts with in its plain body.
+fn strip_plain_reply_fallback(body: &str) -> String {
+    if !body.starts_with("> ") {
+        return body.to_owned();
+    }
+    body.lines()
+        .skip_while(|line| line.starts_with("> "))
+        .skip_while(|line| line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Removes the `<mx-reply>` a reply starts with in its HTML body.
+fn strip_html_reply_fallback(body: &str) -> String {
+    match body.find("</mx-reply>") {
+        Some(end) if body.starts_with("<mx-reply>") => body[end + "</mx-reply>".len()..].to_owned(),
+        _ => body.to_owned(),
+    }
+}
+
+fn strip_reply_fallback(msgtype: &mut MessageType) {
+    let (body, formatted) = match msgtype {
+        MessageType::Text(content) => (&mut content.body, content.formatted.as_mut()),
+        MessageType::Notice(content) => (&mut content.body, content.formatted.as_mut()),
+        MessageType::Emote(content) => (&mut content.body, content.formatted.as_mut()),
+        _ => return,
+    };
+    *body = strip_plain_reply_fallback(body);
+    if let Some(formatted) = formatted {
+        formatted.body = strip_html_reply_fallback(&formatted.body);
+    }
+}
+
+/// The file of an attachment, and its thumbnail if it can have one.
+fn media_mut(
+    msgtype: &mut MessageType,
+) -> Option<(&mut MediaSource, Option<&mut Option<MediaSource>>)> {
+    match msgtype {
+        MessageType::Image(content) => Some((
+            &mut content.source,
+            content.info.as_mut().map(|info| &mut info.thumbnail_source),
+        )),
+        MessageType::Video(content) => Some((
+            &mut content.source,
+            content.info.as_mut().map(|info| &mut info.thumbnail_source),
+        )),
+        MessageType::File(content) => Some((
+            &mut content.source,
+            content.info.as_mut().map(|info| &mut info.thumbnail_source),
+        )),
+        MessageType::Audio(content) => Some((&mut content.source, None)),
+        _ => None,
+    }
+}
+
+fn mimetype(msgtype: &MessageType) -> Option<&str> {
+    match msgtype {
+        MessageType::Image(content) => content.info.as_ref()?.mimetype.as_deref(),
+        MessageType::Video(content) => content.info.as_ref()?.mimetype.as_deref(),
+        MessageType::File(content) => content.info.as_ref()?.mimetype.as_deref(),
+        MessageType::Audio(content) => content.info.as_ref()?.mimetype.as_deref(),
+        _ => None,
+    }
+}
+
+/// The content of the latest edit of the message by its sender, if any.
+async fn latest_edit(
+    room: &Room,
+    message: &OriginalSyncRoomMessageEvent,
+) -> Result<Option<MessageType>, Error> {
+    let mut from = None;
+    loop {
+        // The latest edits come first.
+        let options = RelationsOptions {
+            from,
+            dir: Direction::Backward,
+            include_relations: IncludeRelations::RelationsOfType(RelationType::Replacement),
+            ..Default::default()
+        };
+        let relations = room.relations(message.event_id.clone(), options).await?;
+        let latest = relations
+            .chunk
+            .iter()
+            .filter_map(|event| match event.raw().deserialize().ok()? {
+                AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                    SyncMessageLikeEvent::Original(edit),
+                )) if edit.sender == message.sender => match edit.content.relates_to {
+                    Some(Relation::Replacement(replacement)) => {
+                        Some((edit.origin_server_ts, replacement.new_content.msgtype))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .max_by_key(|(timestamp, _)| *timestamp);
+        if let Some((_, msgtype)) = latest {
+            return Ok(Some(msgtype));
+        }
+
+        match relations.next_batch_token {
+            Some(token) => from = Some(token),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// The message to forward, as last edited, without its relations and reply
+/// fallback.
+pub async fn forwarded_content(room: &Room, event_id: &EventId) -> Result<MessageType, Error> {
+    let event = room.event(event_id, None).await?;
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(message),
+    )) = event.raw().deserialize()?
+    else {
+        return Err(Error::Unsupported);
+    };
+
+    let mut msgtype = match latest_edit(room, &message).await? {
+        Some(msgtype) => msgtype,
+        None => message.content.msgtype,
+    };
+    strip_reply_fallback(&mut msgtype);
+    Ok(msgtype)
+}
+
+/// Encrypts the attachment of a message for encrypted rooms.
+///
+/// Attachments that are encrypted already keep their file, the key is sent
+/// along in the encrypted event. Only unencrypted files are downloaded and
+/// uploaded again, and their thumbnail is dropped.
+pub async fn encrypt_attachment(
+    client: &MatrixClient,
+    msgtype: &MessageType,
+) -> Result<MessageType, Error> {
+    let mut msgtype = msgtype.clone();
+    let Some((source, thumbnail)) = media_mut(&mut msgtype) else {
+        return Ok(msgtype);
+    };
+    if matches!(source, MediaSource::Encrypted(_)) {
+        return Ok(msgtype);
+    }
+
+    let request = MediaRequestParameters {
+        source: source.clone(),
+        format: MediaFormat::File,
+    };
+    let data = client.0.media().get_media_content(&request, true).await?;
+    let file = client
+        .0
+        .upload_encrypted_file(&mut Cursor::new(data))
+        .await?;
+    *source = MediaSource::Encrypted(Box::new(file));
+    if let Some(thumbnail) = thumbnail {
+        if matches!(thumbnail, Some(MediaSource::Plain(_))) {
+            *thumbnail = None;
+        }
+    }
+
+    Ok(msgtype)
+}
+
+/// Decrypts the attachment of a message for unencrypted rooms, whose events
+/// would otherwise give away the key of the file.
+///
+/// The file is downloaded, decrypted and uploaded again, its encrypted
+/// thumbnail is dropped. Unencrypted attachments are kept as they are.
+pub async fn decrypt_attachment(
+    client: &MatrixClient,
+    msgtype: &MessageType,
+) -> Result<MessageType, Error> {
+    let mut msgtype = msgtype.clone();
+    let content_type = mimetype(&msgtype)
+        .unwrap_or("application/octet-stream")
+        .to_owned();
+    let Some((source, thumbnail)) = media_mut(&mut msgtype) else {
+        return Ok(msgtype);
+    };
+    if matches!(source, MediaSource::Plain(_)) {
+        return Ok(msgtype);
+    }
+
+    let request = MediaRequestParameters {
+        source: source.clone(),
+        format: MediaFormat::File,
+    };
+    let data = client.0.media().get_media_content(&request, true).await?;
+    let url = media::upload(client, &content_type, data).await?;
+    *source = MediaSource::Plain(url);
+    if let Some(thumbnail) = thumbnail {
+        if matches!(thumbnail, Some(MediaSource::Encrypted(_))) {
+            *thumbnail = None;
+        }
+    }
+
+    Ok(msgtype)
+}
+
+/// Forwards a message to each room, reporting the outcome for each of them.
+pub async fn forward(
+    client: &MatrixClient,
+    message: &ForwardEvent,
+) -> Result<Vec<ForwardResult>, Error> {
+    let source = room(client, &message.room_id)?;
+    let msgtype = forwarded_content(&source, &EventId::parse(&message.event_id)?).await?;
+    // Encrypted once, for all the encrypted rooms, and decrypted once, for
+    // all the others.
+    let mut encrypted: Option<MessageType> = None;
+    let mut decrypted: Option<MessageType> = None;
+
+    let mut results = Vec::with_capacity(message.target_room_ids.len());
+    for room_id in &message.target_room_ids {
+        let result = async {
+            let target = room(client, room_id)?;
+            let (cached, is_encrypted) = if target.encryption_state().is_encrypted() {
+                (&mut encrypted, true)
+            } else {
+                (&mut decrypted, false)
+            };
+            let content = match cached {
+                Some(content) => content.clone(),
+                None => {
+                    let content = if is_encrypted {
+                        encrypt_attachment(client, &msgtype).await?
+                    } else {
+                        decrypt_attachment(client, &msgtype).await?
+                    };
+                    *cached = Some(content.clone());
+                    content
+                }
+            };
+            let response = target.send(RoomMessageEventContent::new(content)).await?;
+            Ok::<_, Error>(response.event_id)
+        }
+        .await;
+
+        results.push(match result {
+            Ok(event_id) => ForwardResult {
+                room_id: room_id.clone(),
+                event_id: event_id.to_string(),
+                error: Default::default(),
+            },
+            Err(err) => {
+                debug_print!("ForwardEvent: failed to forward to {room_id}: {err:?}");
+                ForwardResult {
+                    room_id: room_id.clone(),
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+            }
+        });
+    }
+
+    Ok(results)
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    let receiver = ForwardEvent::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ForwardEvent = dart_signal.message;
+        debug_print!("ForwardEvent: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => forward(&client, &message).await,
+            None => Err(Error::MissingClient),
+        };
+
+        match result {
+            Ok(results) => EventForwarded {
+                id: message.id,
+                room_id: message.room_id,
+                event_id: message.event_id,
+                results,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("ForwardEvent: err {err:?}");
+                EventForwarded {
+                    id: message.id,
+                    room_id: message.room_id,
+                    event_id: message.event_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{strip_html_reply_fallback, strip_plain_reply_fallback};
+
+    #[test]
+    fn strips_plain_reply_fallback() {
+        assert_eq!(
+            strip_plain_reply_fallback("> <@alice:example.org> hi\n> there\n\nhello"),
+            "hello"
+        );
+        assert_eq!(
+            strip_plain_reply_fallback("hello\n> quote"),
+            "hello\n> quote"
+        );
+    }
+
+    #[test]
+    fn strips_html_reply_fallback() {
+        assert_eq!(
+            strip_html_reply_fallback(
+                "<mx-reply><blockquote>hi</blockquote></mx-reply><b>hello</b>"
+            ),
+            "<b>hello</b>"
+        );
+        assert_eq!(strip_html_reply_fallback("<b>hello</b>"), "<b>hello</b>");
+    }
+}
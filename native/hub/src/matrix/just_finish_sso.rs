@@ -2,7 +2,10 @@ use matrix_sdk::{SessionMeta, SessionTokens};
 use rinf::debug_print;
 
 use crate::{
-    matrix::client::{ArcMatrixClients, MatrixClient, OidcError},
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient, OidcError},
+        session::SessionStore,
+    },
     messages::*,
 };
 
@@ -30,7 +33,7 @@ pub async fn just_finish_sso(
     }
 }
 
-pub async fn communicate(clients: ArcMatrixClients) {
+pub async fn communicate(clients: ArcMatrixClients, sessions: SessionStore) {
     let receiver = JustFinishSso::get_dart_signal_receiver();
     while let Some(dart_signal) = receiver.recv().await {
         let message: JustFinishSso = dart_signal.message;
@@ -43,6 +46,9 @@ pub async fn communicate(clients: ArcMatrixClients) {
             Some(client) => match just_finish_sso(client, message.callback_url).await {
                 Ok((meta, tokens)) => {
                     debug_print!("JustFinishSso: ok");
+                    sessions
+                        .track(clients.clone(), message.id.clone(), client.clone())
+                        .await;
                     JustSsoTokens {
                         id: message.id,
                         device_id: meta.device_id.to_string(),
@@ -1,9 +1,13 @@
 use matrix_sdk::ClientBuildError;
 use rinf::debug_print;
+use std::path::PathBuf;
 use url::Url;
 
 use crate::{
-    matrix::client::{ArcMatrixClients, MatrixClient, OidcConfiguration, OidcError, OidcPrompt},
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient, OidcConfiguration, OidcError, OidcPrompt},
+        session::SessionStore,
+    },
     messages::*,
 };
 
@@ -15,9 +19,11 @@ pub enum Error {
 
 pub async fn get_oidc_url(
     url: String,
+    store_path: PathBuf,
+    store_passphrase: Option<&str>,
     oidc_configuration: &OidcConfiguration,
 ) -> Result<(MatrixClient, Url), Error> {
-    let client = MatrixClient::from_name_or_homeserver_url(&url)
+    let client = MatrixClient::with_store(&url, store_path, store_passphrase)
         .await
         .map_err(Error::Client)?;
 
@@ -28,19 +34,23 @@ pub async fn get_oidc_url(
         .map_err(Error::Oidc)
 }
 
-pub async fn communicate(clients: ArcMatrixClients) {
+pub async fn communicate(clients: ArcMatrixClients, sessions: SessionStore) {
     let receiver = JustGetOidcUrls::get_dart_signal_receiver();
     while let Some(dart_signal) = receiver.recv().await {
         let message: JustGetOidcUrls = dart_signal.message;
         debug_print!("JustGetOidcUrls: received {message:?}");
 
-        {
-            let mut c = clients.lock().await;
-            c.remove(&message.id);
+        // A new attempt abandons the previous one.
+        let previous = clients.lock().await.remove(&message.id);
+        if let Some(previous) = previous {
+            sessions.discard(previous).await;
         }
 
+        let store_path = sessions.new_store_path();
         match get_oidc_url(
             message.name_or_homeserver_url,
+            store_path.clone(),
+            sessions.passphrase().as_deref(),
             &OidcConfiguration {
                 client_name: Some(message.client_name),
                 redirect_uri: message.redirect_uri,
@@ -66,6 +76,7 @@ pub async fn communicate(clients: ArcMatrixClients) {
             }
             Err(err) => {
                 debug_print!("JustGetOidcUrls: err {err:?}");
+                sessions.remove_store(&store_path).await;
                 JustOidcUrls {
                     id: message.id,
                     url: "".to_string(),
@@ -97,7 +108,8 @@ mod tests {
             static_registrations: Default::default(),
         };
         // let homeserver_login_details = client.homeserver_login_details().await;
-        let url = get_oidc_url(url, oidc_configuration).await;
+        let store_path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let url = get_oidc_url(url, store_path, None, oidc_configuration).await;
 
         assert!(url.is_ok());
     }
@@ -0,0 +1,111 @@
+use matrix_sdk::ClientBuildError;
+use rinf::debug_print;
+use std::path::PathBuf;
+use url::Url;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient, OidcConfiguration, OidcError, OidcPrompt},
+        session::SessionStore,
+    },
+    messages::*,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Oidc(OidcError),
+    Client(ClientBuildError),
+    RegistrationUnsupported,
+}
+
+/// Builds the URL of the registration page of the authorization server.
+///
+/// Once the web view has succeeded, the callback is handled by
+/// `just_finish_sso`, exactly like a login.
+pub async fn get_oidc_registration_url(
+    url: String,
+    store_path: PathBuf,
+    store_passphrase: Option<&str>,
+    oidc_configuration: &OidcConfiguration,
+) -> Result<(MatrixClient, Url), Error> {
+    let client = MatrixClient::with_store(&url, store_path, store_passphrase)
+        .await
+        .map_err(Error::Client)?;
+
+    let login_details = client.homeserver_login_details().await;
+    let supports_create = login_details
+        .supported_oidc_prompts
+        .iter()
+        .any(|prompt| matches!(prompt, OidcPrompt::Create));
+    if !supports_create {
+        return Err(Error::RegistrationUnsupported);
+    }
+
+    client
+        .url_for_oidc(oidc_configuration, Some(OidcPrompt::Create), None, None)
+        .await
+        .map(|data| (client, data.url))
+        .map_err(Error::Oidc)
+}
+
+pub async fn communicate(clients: ArcMatrixClients, sessions: SessionStore) {
+    let receiver = JustGetOidcRegistrationUrl::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: JustGetOidcRegistrationUrl = dart_signal.message;
+        debug_print!("JustGetOidcRegistrationUrl: received {message:?}");
+
+        // A new attempt abandons the previous one.
+        let previous = clients.lock().await.remove(&message.id);
+        if let Some(previous) = previous {
+            sessions.discard(previous).await;
+        }
+
+        let store_path = sessions.new_store_path();
+        match get_oidc_registration_url(
+            message.name_or_homeserver_url,
+            store_path.clone(),
+            sessions.passphrase().as_deref(),
+            &OidcConfiguration {
+                client_name: Some(message.client_name),
+                redirect_uri: message.redirect_uri,
+                client_uri: message.client_uri,
+                logo_uri: Some(message.logo_uri),
+                tos_uri: Some(message.tos_uri),
+                policy_uri: Some(message.policy_uri),
+                static_registrations: Default::default(),
+            },
+        )
+        .await
+        {
+            Ok((client, url)) => {
+                let mut c = clients.lock().await;
+                c.insert(message.id.clone(), client);
+                debug_print!("JustGetOidcRegistrationUrl: ok {url:?}");
+                JustOidcRegistrationUrl {
+                    id: message.id,
+                    url: url.into(),
+                    registration_unsupported: false,
+                    error: "".to_string(),
+                }
+                .send_signal_to_dart();
+            }
+            Err(err) => {
+                debug_print!("JustGetOidcRegistrationUrl: err {err:?}");
+                sessions.remove_store(&store_path).await;
+                JustOidcRegistrationUrl {
+                    id: message.id,
+                    url: "".to_string(),
+                    registration_unsupported: matches!(err, Error::RegistrationUnsupported),
+                    error: match err {
+                        Error::Oidc(err) => err.to_string(),
+                        Error::Client(err) => err.to_string(),
+                        Error::RegistrationUnsupported => {
+                            "registration unsupported by the homeserver".to_string()
+                        }
+                    },
+                }
+                .send_signal_to_dart();
+            }
+        };
+    }
+}
@@ -0,0 +1,112 @@
+use std::io::Cursor;
+
+use image::{metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use matrix_sdk::ruma::OwnedMxcUri;
+
+use crate::matrix::client::MatrixClient;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid content type: {0}")]
+    ContentType(String),
+    #[error(transparent)]
+    Http(#[from] matrix_sdk::HttpError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Image(#[from] image::ImageError),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// Uploads a file to the content repository of the homeserver.
+pub async fn upload(
+    client: &MatrixClient,
+    content_type: &str,
+    data: Vec<u8>,
+) -> Result<OwnedMxcUri, Error> {
+    let content_type: mime::Mime = content_type
+        .parse()
+        .map_err(|_| Error::ContentType(content_type.to_owned()))?;
+
+    let response = client.0.media().upload(&content_type, data, None).await?;
+
+    Ok(response.content_uri)
+}
+
+/// Scales an image down to fit in a `max_size` square, keeping its ratio,
+/// on a thread where blocking is fine.
+///
+/// The image is turned upright as its EXIF orientation says. JPEG images
+/// stay JPEG, the others become PNG. Images that already fit and need no
+/// change are returned as they are. Returns the data and its content type.
+pub async fn resize_image(data: Vec<u8>, max_size: u32) -> Result<(Vec<u8>, String), Error> {
+    tokio::task::spawn_blocking(move || resize(data, max_size)).await?
+}
+
+fn resize(data: Vec<u8>, max_size: u32) -> Result<(Vec<u8>, String), Error> {
+    let original = image::guess_format(&data)?;
+    let mut decoder = ImageReader::with_format(Cursor::new(&data), original).into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let (format, content_type) = match original {
+        ImageFormat::Jpeg => (ImageFormat::Jpeg, mime::IMAGE_JPEG),
+        _ => (ImageFormat::Png, mime::IMAGE_PNG),
+    };
+    let fits = image.width() <= max_size && image.height() <= max_size;
+    if fits && original == format && orientation == Orientation::NoTransforms {
+        return Ok((data, content_type.to_string()));
+    }
+
+    let image = if fits {
+        image
+    } else {
+        image.thumbnail(max_size, max_size)
+    };
+    // JPEG has no alpha channel.
+    let image = match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8()),
+        _ => image,
+    };
+    let mut resized = Vec::new();
+    image.write_to(&mut Cursor::new(&mut resized), format)?;
+    Ok((resized, content_type.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat};
+
+    use super::resize;
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn resize_scales_down_only() {
+        let (data, content_type) = resize(encode(40, 20, ImageFormat::Png), 10).unwrap();
+        let image = image::load_from_memory(&data).unwrap();
+        assert_eq!((image.width(), image.height()), (10, 5));
+        assert_eq!(content_type, "image/png");
+
+        let original = encode(4, 4, ImageFormat::Gif);
+        let (data, content_type) = resize(original, 512).unwrap();
+        let image = image::load_from_memory_with_format(&data, ImageFormat::Png).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 4));
+        assert_eq!(content_type, "image/png");
+
+        let original = encode(8, 8, ImageFormat::Jpeg);
+        let (data, content_type) = resize(original.clone(), 512).unwrap();
+        assert_eq!(data, original);
+        assert_eq!(content_type, "image/jpeg");
+    }
+}
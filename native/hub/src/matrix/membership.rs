@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use matrix_sdk::{
+    ruma::{
+        api::client::error::{ErrorBody, ErrorKind, RetryAfter},
+        IdParseError, OwnedUserId, RoomId, UserId,
+    },
+    Room, RoomState,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::client::{ArcMatrixClients, MatrixClient},
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("The power level of the user is too low for this action.")]
+    InsufficientPowerLevel,
+    /// Refused by the server, with its explanation.
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("The user isn't in the room.")]
+    NotInRoom,
+    #[error("Too many requests, try again later.")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("This action needs a user id.")]
+    MissingUserId,
+    #[error("The membership action isn't specified.")]
+    UnspecifiedAction,
+    #[error("missing client")]
+    MissingClient,
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(matrix_sdk::Error),
+}
+
+impl From<matrix_sdk::Error> for Error {
+    fn from(value: matrix_sdk::Error) -> Self {
+        match value.client_api_error_kind() {
+            Some(ErrorKind::Forbidden { .. }) => {
+                let message = match value.as_client_api_error().map(|err| &err.body) {
+                    Some(ErrorBody::Standard { message, .. }) => message.clone(),
+                    _ => value.to_string(),
+                };
+                Error::Forbidden(message)
+            }
+            Some(ErrorKind::LimitExceeded { retry_after }) => Error::RateLimited {
+                retry_after: match retry_after {
+                    Some(RetryAfter::Delay(delay)) => Some(*delay),
+                    _ => None,
+                },
+            },
+            _ => Error::Sdk(value),
+        }
+    }
+}
+
+impl Error {
+    fn kind(&self) -> MembershipErrorKind {
+        match self {
+            Error::InsufficientPowerLevel => MembershipErrorKind::InsufficientPowerLevel,
+            Error::Forbidden(_) => MembershipErrorKind::Forbidden,
+            Error::NotInRoom => MembershipErrorKind::NotInRoom,
+            Error::RateLimited { .. } => MembershipErrorKind::RateLimited,
+            _ => MembershipErrorKind::Other,
+        }
+    }
+}
+
+/// The room, if the user has the membership the action expects.
+fn room_for(
+    client: &MatrixClient,
+    room_id: &RoomId,
+    action: MembershipAction,
+) -> Result<Room, Error> {
+    let expected: &[RoomState] = match action {
+        MembershipAction::AcceptInvite | MembershipAction::DeclineInvite => &[RoomState::Invited],
+        // Rooms the user was banned from can be forgotten too.
+        MembershipAction::Forget => &[RoomState::Left, RoomState::Banned],
+        _ => &[RoomState::Joined],
+    };
+
+    client
+        .0
+        .get_room(room_id)
+        .filter(|room| expected.contains(&room.state()))
+        .ok_or(Error::NotInRoom)
+}
+
+/// Fails early, without a request, when the own power level is known to be
+/// too low.
+async fn ensure_allowed(
+    client: &MatrixClient,
+    room: &Room,
+    action: MembershipAction,
+) -> Result<(), Error> {
+    let Some(own_user_id) = client.0.user_id() else {
+        return Ok(());
+    };
+    let allowed = match action {
+        MembershipAction::Invite => room.can_user_invite(own_user_id).await?,
+        MembershipAction::Kick => room.can_user_kick(own_user_id).await?,
+        MembershipAction::Ban | MembershipAction::Unban => room.can_user_ban(own_user_id).await?,
+        _ => true,
+    };
+
+    if !allowed {
+        return Err(Error::InsufficientPowerLevel);
+    }
+    Ok(())
+}
+
+pub async fn update_membership(
+    client: &MatrixClient,
+    message: &UpdateMembership,
+) -> Result<(), Error> {
+    let action = message.action();
+    if action == MembershipAction::Unspecified {
+        return Err(Error::UnspecifiedAction);
+    }
+    let room = room_for(client, &RoomId::parse(&message.room_id)?, action)?;
+    ensure_allowed(client, &room, action).await?;
+
+    let user_id = || -> Result<OwnedUserId, Error> {
+        match message.user_id.as_str() {
+            "" => Err(Error::MissingUserId),
+            user_id => Ok(UserId::parse(user_id)?),
+        }
+    };
+    let reason = Some(message.reason.as_str()).filter(|r| !r.is_empty());
+
+    match action {
+        MembershipAction::Unspecified => return Err(Error::UnspecifiedAction),
+        MembershipAction::Invite => room.invite_user_by_id(&user_id()?).await?,
+        MembershipAction::Kick => room.kick_user(&user_id()?, reason).await?,
+        MembershipAction::Ban => room.ban_user(&user_id()?, reason).await?,
+        MembershipAction::Unban => room.unban_user(&user_id()?, reason).await?,
+        MembershipAction::Leave => room.leave().await?,
+        MembershipAction::Forget => room.forget().await?,
+        MembershipAction::AcceptInvite => room.join().await?,
+        MembershipAction::DeclineInvite => {
+            // The inviter is only known while the invite is pending.
+            let inviter = if message.ignore_inviter {
+                room.invite_details()
+                    .await?
+                    .inviter
+                    .map(|member| member.user_id().to_owned())
+            } else {
+                None
+            };
+            room.leave().await?;
+            if let Some(inviter) = inviter {
+                client.0.account().ignore_user(&inviter).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    let receiver = UpdateMembership::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: UpdateMembership = dart_signal.message;
+        debug_print!("UpdateMembership: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => update_membership(&client, &message).await,
+            None => Err(Error::MissingClient),
+        };
+
+        let (error_kind, retry_after_ms, error) = match &result {
+            Ok(()) => (MembershipErrorKind::None, 0, String::new()),
+            Err(err) => {
+                debug_print!("UpdateMembership: err {err:?}");
+                let retry_after_ms = match err {
+                    Error::RateLimited {
+                        retry_after: Some(delay),
+                    } => delay.as_millis() as u64,
+                    _ => 0,
+                };
+                (err.kind(), retry_after_ms, err.to_string())
+            }
+        };
+
+        MembershipUpdated {
+            id: message.id,
+            room_id: message.room_id,
+            action: message.action,
+            user_id: message.user_id,
+            error_kind: error_kind.into(),
+            retry_after_ms,
+            error,
+        }
+        .send_signal_to_dart();
+    }
+}
@@ -1,13 +1,118 @@
+mod account_management;
 mod client;
+mod create_room;
+mod devices;
+mod drafts;
+mod forward;
 mod init_client;
 mod just_finish_sso;
 mod just_get_oidc_login_urls;
+mod just_get_oidc_registration_url;
+mod media;
+mod membership;
+mod notification_settings;
+mod notifications;
+mod permalinks;
+mod pinned_events;
+mod power_levels;
+mod profile;
+mod pushers;
+mod reauthenticate;
+mod receipts;
+mod recent_emoji;
+mod room_directory;
+mod room_list;
+mod room_settings;
+mod search;
+mod search_index;
+mod session;
+mod store_key;
+mod subscriptions;
+mod sync;
+mod threads;
+mod timeline;
+mod typing;
+mod user_directory;
 
-use crate::matrix::client::ArcMatrixClients;
+use crate::{
+    matrix::{
+        client::ArcMatrixClients, search_index::SearchIndexes, session::SessionStore,
+        store_key::StoreKey, timeline::Timelines,
+    },
+    messages::*,
+};
 
 pub async fn init() {
+    // Nothing that logs in can be handled before Dart tells where to persist
+    // the stores.
+    let receiver = InitMatrix::get_dart_signal_receiver();
+    let Some(dart_signal) = receiver.recv().await else {
+        return;
+    };
+    let message = dart_signal.message;
+    let store_key = StoreKey::from_bytes(&message.store_key);
+    if store_key.is_none() {
+        if !message.no_secure_storage {
+            rinf::debug_print!("no store key, the hub won't start");
+            MatrixInitFailed {
+                error: "The store key can't be read from the secure storage.".to_owned(),
+            }
+            .send_signal_to_dart();
+            return;
+        }
+        rinf::debug_print!("no secure storage, the sessions and stores won't be encrypted");
+    }
+    let sessions = SessionStore::new(message.data_directory, store_key.clone());
+    if let Err(err) = sessions.remove_orphan_stores().await {
+        rinf::debug_print!("failed to remove the stores of abandoned logins: {err:?}");
+    }
+
     let clients: ArcMatrixClients = Default::default();
+    let timelines: Timelines = Default::default();
+    let indexes = SearchIndexes::new(store_key);
     tokio::spawn(init_client::init_client(clients.clone()));
-    tokio::spawn(just_get_oidc_login_urls::communicate(clients.clone()));
-    tokio::spawn(just_finish_sso::communicate(clients));
+    tokio::spawn(account_management::communicate(clients.clone()));
+    tokio::spawn(just_get_oidc_login_urls::communicate(
+        clients.clone(),
+        sessions.clone(),
+    ));
+    tokio::spawn(just_get_oidc_registration_url::communicate(
+        clients.clone(),
+        sessions.clone(),
+    ));
+    tokio::spawn(just_finish_sso::communicate(
+        clients.clone(),
+        sessions.clone(),
+    ));
+    tokio::spawn(reauthenticate::communicate(
+        clients.clone(),
+        sessions.clone(),
+    ));
+    tokio::spawn(notifications::communicate(clients.clone(), sessions));
+    tokio::spawn(devices::communicate(clients.clone()));
+    tokio::spawn(pushers::communicate(clients.clone()));
+    tokio::spawn(notification_settings::communicate(clients.clone()));
+    tokio::spawn(sync::communicate(clients.clone(), indexes.clone()));
+    tokio::spawn(create_room::communicate(clients.clone()));
+    tokio::spawn(membership::communicate(clients.clone()));
+    tokio::spawn(power_levels::communicate(clients.clone()));
+    tokio::spawn(room_list::communicate(clients.clone()));
+    tokio::spawn(room_settings::communicate(clients.clone()));
+    tokio::spawn(pinned_events::communicate(clients.clone()));
+    tokio::spawn(timeline::communicate(
+        clients.clone(),
+        timelines,
+        indexes.clone(),
+    ));
+    tokio::spawn(recent_emoji::communicate(clients.clone()));
+    tokio::spawn(forward::communicate(clients.clone()));
+    tokio::spawn(threads::communicate(clients.clone()));
+    tokio::spawn(receipts::communicate(clients.clone()));
+    tokio::spawn(typing::communicate(clients.clone()));
+    tokio::spawn(drafts::communicate(clients.clone()));
+    tokio::spawn(search::communicate(clients.clone(), indexes));
+    tokio::spawn(user_directory::communicate(clients.clone()));
+    tokio::spawn(profile::communicate(clients.clone()));
+    tokio::spawn(room_directory::communicate(clients.clone()));
+    tokio::spawn(permalinks::communicate(clients));
 }
@@ -0,0 +1,379 @@
+use matrix_sdk::{
+    notification_settings::{
+        IsEncrypted, IsOneToOne, NotificationSettings, NotificationSettingsError,
+        RoomNotificationMode,
+    },
+    ruma::{
+        push::{PredefinedOverrideRuleId, PredefinedUnderrideRuleId, RuleKind},
+        IdParseError, RoomId,
+    },
+};
+use rinf::debug_print;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        subscriptions::Subscriptions,
+    },
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Settings(#[from] NotificationSettingsError),
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+impl From<RoomNotificationMode> for NotificationMode {
+    fn from(value: RoomNotificationMode) -> Self {
+        match value {
+            RoomNotificationMode::AllMessages => Self::AllMessages,
+            RoomNotificationMode::MentionsAndKeywordsOnly => Self::MentionsAndKeywords,
+            RoomNotificationMode::Mute => Self::Mute,
+        }
+    }
+}
+
+impl NotificationMode {
+    /// `None` stands for the default mode.
+    fn into_sdk(self) -> Option<RoomNotificationMode> {
+        match self {
+            NotificationMode::Unspecified => None,
+            NotificationMode::AllMessages => Some(RoomNotificationMode::AllMessages),
+            NotificationMode::MentionsAndKeywords => {
+                Some(RoomNotificationMode::MentionsAndKeywordsOnly)
+            }
+            NotificationMode::Mute => Some(RoomNotificationMode::Mute),
+        }
+    }
+}
+
+impl NotificationToggle {
+    /// The predefined rule behind a toggle.
+    fn rule(self) -> (RuleKind, &'static str) {
+        match self {
+            NotificationToggle::Calls => (
+                RuleKind::Underride,
+                PredefinedUnderrideRuleId::Call.as_str(),
+            ),
+            NotificationToggle::Invites => (
+                RuleKind::Override,
+                PredefinedOverrideRuleId::InviteForMe.as_str(),
+            ),
+            NotificationToggle::UserMentions => (
+                RuleKind::Override,
+                PredefinedOverrideRuleId::IsUserMention.as_str(),
+            ),
+            NotificationToggle::RoomMentions => (
+                RuleKind::Override,
+                PredefinedOverrideRuleId::IsRoomMention.as_str(),
+            ),
+        }
+    }
+}
+
+async fn is_enabled(settings: &NotificationSettings, toggle: NotificationToggle) -> bool {
+    let (kind, rule_id) = toggle.rule();
+    settings
+        .is_push_rule_enabled(kind, rule_id)
+        .await
+        .unwrap_or(false)
+}
+
+/// A snapshot of the account-wide settings.
+pub async fn settings_state(
+    id: String,
+    settings: &NotificationSettings,
+) -> NotificationSettingsState {
+    let dm_mode = settings
+        .get_default_room_notification_mode(IsEncrypted::Yes, IsOneToOne::Yes)
+        .await;
+    let group_mode = settings
+        .get_default_room_notification_mode(IsEncrypted::Yes, IsOneToOne::No)
+        .await;
+
+    NotificationSettingsState {
+        id,
+        dm_mode: NotificationMode::from(dm_mode).into(),
+        group_mode: NotificationMode::from(group_mode).into(),
+        keywords: settings.enabled_keywords().await.into_iter().collect(),
+        calls_enabled: is_enabled(settings, NotificationToggle::Calls).await,
+        invites_enabled: is_enabled(settings, NotificationToggle::Invites).await,
+        user_mentions_enabled: is_enabled(settings, NotificationToggle::UserMentions).await,
+        room_mentions_enabled: is_enabled(settings, NotificationToggle::RoomMentions).await,
+        error: Default::default(),
+    }
+}
+
+/// Sets the default mode of either direct or group chats, encrypted or not.
+pub async fn set_default_mode(
+    settings: &NotificationSettings,
+    is_direct: bool,
+    mode: RoomNotificationMode,
+) -> Result<(), Error> {
+    for is_encrypted in [IsEncrypted::Yes, IsEncrypted::No] {
+        settings
+            .set_default_room_notification_mode(
+                is_encrypted,
+                IsOneToOne::from(is_direct),
+                mode.clone(),
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn set_toggle(
+    settings: &NotificationSettings,
+    toggle: NotificationToggle,
+    enabled: bool,
+) -> Result<(), Error> {
+    let (kind, rule_id) = toggle.rule();
+    settings
+        .set_push_rule_enabled(kind, rule_id, enabled)
+        .await?;
+    Ok(())
+}
+
+pub async fn room_mode(
+    client: &MatrixClient,
+    room_id: &str,
+) -> Result<RoomNotificationModeState, Error> {
+    let room = client
+        .0
+        .get_room(&RoomId::parse(room_id)?)
+        .ok_or(Error::RoomNotFound)?;
+    let user_defined = room.user_defined_notification_mode().await;
+    let is_default = user_defined.is_none();
+    let mode = match user_defined {
+        Some(mode) => Some(mode),
+        None => room.notification_mode().await,
+    };
+
+    Ok(RoomNotificationModeState {
+        id: Default::default(),
+        room_id: room_id.to_owned(),
+        mode: mode.map(NotificationMode::from).unwrap_or_default().into(),
+        is_default,
+        error: Default::default(),
+    })
+}
+
+/// Sets the mode of a room, `None` going back to the default mode.
+pub async fn set_room_mode(
+    client: &MatrixClient,
+    settings: &NotificationSettings,
+    room_id: &str,
+    mode: Option<RoomNotificationMode>,
+) -> Result<RoomNotificationModeState, Error> {
+    let parsed_room_id = RoomId::parse(room_id)?;
+    match mode {
+        Some(mode) => {
+            settings
+                .set_room_notification_mode(&parsed_room_id, mode)
+                .await?
+        }
+        None => {
+            settings
+                .delete_user_defined_room_rules(&parsed_room_id)
+                .await?
+        }
+    }
+    room_mode(client, room_id).await
+}
+
+/// Streams the account-wide settings to Dart whenever the push rules change,
+/// including from other devices.
+async fn watch(id: String, client: MatrixClient) {
+    let settings = client.0.notification_settings().await;
+    let mut changes = settings.subscribe_to_changes();
+    loop {
+        match changes.recv().await {
+            Ok(()) | Err(RecvError::Lagged(_)) => {
+                settings_state(id.clone(), &settings)
+                    .await
+                    .send_signal_to_dart();
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+/// Replies to a read or a change of the account-wide settings with their
+/// current state.
+async fn reply(id: String, result: Result<NotificationSettings, Error>) {
+    match result {
+        Ok(settings) => settings_state(id, &settings).await.send_signal_to_dart(),
+        Err(err) => {
+            debug_print!("NotificationSettings: err {err:?}");
+            NotificationSettingsState {
+                id,
+                error: err.to_string(),
+                ..Default::default()
+            }
+            .send_signal_to_dart();
+        }
+    }
+}
+
+async fn communicate_get(clients: ArcMatrixClients) {
+    let mut watched = Subscriptions::default();
+    let receiver = GetNotificationSettings::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetNotificationSettings = dart_signal.message;
+        debug_print!("GetNotificationSettings: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => {
+                let stream = watch(message.id.clone(), client.clone());
+                watched.start(message.id.clone(), &message.id, &client, stream);
+                Ok(client.0.notification_settings().await)
+            }
+            Err(err) => {
+                watched.stop(&message.id);
+                Err(err)
+            }
+        };
+        reply(message.id, result).await;
+    }
+}
+
+async fn communicate_set_default(clients: ArcMatrixClients) {
+    let receiver = SetDefaultNotificationMode::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SetDefaultNotificationMode = dart_signal.message;
+        debug_print!("SetDefaultNotificationMode: received {message:?}");
+
+        // There's no default for the default mode, fall back to all messages.
+        let mode = message
+            .mode()
+            .into_sdk()
+            .unwrap_or(RoomNotificationMode::AllMessages);
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => {
+                let settings = client.0.notification_settings().await;
+                set_default_mode(&settings, message.is_direct, mode)
+                    .await
+                    .map(|()| settings)
+            }
+            Err(err) => Err(err),
+        };
+        reply(message.id, result).await;
+    }
+}
+
+async fn communicate_keywords(clients: ArcMatrixClients) {
+    let receiver = UpdateNotificationKeyword::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: UpdateNotificationKeyword = dart_signal.message;
+        debug_print!("UpdateNotificationKeyword: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => {
+                let settings = client.0.notification_settings().await;
+                let update = if message.remove {
+                    settings.remove_keyword(&message.keyword).await
+                } else {
+                    settings.add_keyword(message.keyword).await
+                };
+                update.map(|()| settings).map_err(Error::from)
+            }
+            Err(err) => Err(err),
+        };
+        reply(message.id, result).await;
+    }
+}
+
+async fn communicate_toggle(clients: ArcMatrixClients) {
+    let receiver = SetNotificationToggle::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SetNotificationToggle = dart_signal.message;
+        debug_print!("SetNotificationToggle: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => {
+                let settings = client.0.notification_settings().await;
+                set_toggle(&settings, message.toggle(), message.enabled)
+                    .await
+                    .map(|()| settings)
+            }
+            Err(err) => Err(err),
+        };
+        reply(message.id, result).await;
+    }
+}
+
+async fn reply_room(id: String, room_id: String, result: Result<RoomNotificationModeState, Error>) {
+    match result {
+        Ok(state) => RoomNotificationModeState { id, ..state }.send_signal_to_dart(),
+        Err(err) => {
+            debug_print!("RoomNotificationMode: err {err:?}");
+            RoomNotificationModeState {
+                id,
+                room_id,
+                error: err.to_string(),
+                ..Default::default()
+            }
+            .send_signal_to_dart();
+        }
+    }
+}
+
+async fn communicate_get_room(clients: ArcMatrixClients) {
+    let receiver = GetRoomNotificationMode::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetRoomNotificationMode = dart_signal.message;
+        debug_print!("GetRoomNotificationMode: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => room_mode(&client, &message.room_id).await,
+            Err(err) => Err(err),
+        };
+        reply_room(message.id, message.room_id, result).await;
+    }
+}
+
+async fn communicate_set_room(clients: ArcMatrixClients) {
+    let receiver = SetRoomNotificationMode::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SetRoomNotificationMode = dart_signal.message;
+        debug_print!("SetRoomNotificationMode: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => {
+                let settings = client.0.notification_settings().await;
+                let mode = message.mode().into_sdk();
+                set_room_mode(&client, &settings, &message.room_id, mode).await
+            }
+            Err(err) => Err(err),
+        };
+        reply_room(message.id, message.room_id, result).await;
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_get(clients.clone()),
+        communicate_set_default(clients.clone()),
+        communicate_keywords(clients.clone()),
+        communicate_toggle(clients.clone()),
+        communicate_get_room(clients.clone()),
+        communicate_set_room(clients),
+    );
+}
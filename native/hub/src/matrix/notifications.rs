@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use matrix_sdk::{
+    ruma::{
+        events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent},
+        EventId, IdParseError, RoomId,
+    },
+    SessionChange,
+};
+use matrix_sdk_ui::notification_client::{
+    Error as NotificationClientError, NotificationClient, NotificationEvent, NotificationItem,
+    NotificationProcessSetup, NotificationStatus,
+};
+use rinf::debug_print;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        session::{RestoreError, SessionStore},
+    },
+    messages::*,
+};
+
+/// Event types that ring, rather than just notify.
+const CALL_EVENT_TYPES: &[&str] = &[
+    "m.call.invite",
+    "m.call.notify",
+    "org.matrix.msc4075.call.notify",
+    "org.matrix.msc4075.rtc.notification",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Restore(#[from] RestoreError),
+    #[error(transparent)]
+    Store(#[from] std::io::Error),
+    #[error(transparent)]
+    Notification(#[from] NotificationClientError),
+    #[error("No session was persisted for this account.")]
+    UnknownAccount,
+}
+
+/// Finds a client for the account: the one of the running app if there is
+/// one, otherwise one restored from the persisted stores, without syncing.
+async fn client_for(
+    clients: &ArcMatrixClients,
+    sessions: &SessionStore,
+    restored: &mut HashMap<String, Restored>,
+    user_id: &str,
+) -> Result<MatrixClient, Error> {
+    let running = clients
+        .lock()
+        .await
+        .values()
+        .find(|client| client.0.user_id().is_some_and(|id| id.as_str() == user_id))
+        .cloned();
+    if let Some(client) = running {
+        return Ok(client);
+    }
+
+    if let Some(previous) = restored.get_mut(user_id) {
+        if !previous.is_logged_out() {
+            return Ok(previous.client.clone());
+        }
+        restored.remove(user_id);
+    }
+
+    let session = sessions.load(user_id).await?.ok_or(Error::UnknownAccount)?;
+    let client = session.restore(sessions).await?;
+    restored.insert(
+        user_id.to_owned(),
+        Restored {
+            client: client.clone(),
+            session_changes: client.0.subscribe_to_session_changes(),
+        },
+    );
+    sessions.track_in_background(client.clone());
+
+    Ok(client)
+}
+
+/// A client restored from the persisted stores, kept until its tokens are
+/// found to be invalid.
+struct Restored {
+    client: MatrixClient,
+    session_changes: broadcast::Receiver<SessionChange>,
+}
+
+impl Restored {
+    fn is_logged_out(&mut self) -> bool {
+        loop {
+            match self.session_changes.try_recv() {
+                Ok(SessionChange::UnknownToken { .. }) | Err(TryRecvError::Closed) => return true,
+                Ok(SessionChange::TokensRefreshed) | Err(TryRecvError::Lagged(_)) => continue,
+                Err(TryRecvError::Empty) => return false,
+            }
+        }
+    }
+}
+
+/// Fetches and decrypts the event a push is about.
+///
+/// Returns `None` if the event can't be found or shouldn't notify, for
+/// example because of the push rules.
+pub async fn get_notification(
+    client: &MatrixClient,
+    room_id: &str,
+    event_id: &str,
+) -> Result<Option<NotificationItem>, Error> {
+    let room_id = RoomId::parse(room_id)?;
+    let event_id = EventId::parse(event_id)?;
+
+    // The app may be running in another process, which owns the sync.
+    let notification_client = NotificationClient::new(
+        client.0.clone(),
+        NotificationProcessSetup::MultipleProcesses,
+    )
+    .await?;
+
+    match notification_client
+        .get_notification(&room_id, &event_id)
+        .await?
+    {
+        NotificationStatus::Event(item) => Ok(Some(item)),
+        NotificationStatus::EventNotFound | NotificationStatus::EventFilteredOut => Ok(None),
+    }
+}
+
+fn body(event: &NotificationEvent) -> String {
+    match event {
+        NotificationEvent::Timeline(event) => match event.as_ref() {
+            AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                SyncMessageLikeEvent::Original(message),
+            )) => message.content.body().to_owned(),
+            _ => Default::default(),
+        },
+        NotificationEvent::Invite(_) => Default::default(),
+    }
+}
+
+fn is_call(event: &NotificationEvent) -> bool {
+    match event {
+        NotificationEvent::Timeline(event) => {
+            CALL_EVENT_TYPES.contains(&event.event_type().to_string().as_str())
+        }
+        NotificationEvent::Invite(_) => false,
+    }
+}
+
+impl NotificationContent {
+    fn from_item(message: GetNotification, item: NotificationItem) -> Self {
+        Self {
+            body: body(&item.event),
+            is_call: is_call(&item.event),
+            is_invite: matches!(item.event, NotificationEvent::Invite(_)),
+            user_id: message.user_id,
+            room_id: message.room_id,
+            event_id: message.event_id,
+            found: true,
+            sender_name: item.sender_display_name.unwrap_or_default(),
+            sender_avatar_url: item.sender_avatar_url.unwrap_or_default(),
+            room_name: item.room_computed_display_name,
+            room_avatar_url: item.room_avatar_url.unwrap_or_default(),
+            is_direct: item.is_direct_message_room,
+            is_mention: item.has_mention.unwrap_or_default(),
+            is_noisy: item.is_noisy.unwrap_or_default(),
+            error: Default::default(),
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients, sessions: SessionStore) {
+    let mut restored = HashMap::new();
+    let receiver = GetNotification::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetNotification = dart_signal.message;
+        debug_print!("GetNotification: received {message:?}");
+
+        let item = match client_for(&clients, &sessions, &mut restored, &message.user_id).await {
+            Ok(client) => get_notification(&client, &message.room_id, &message.event_id).await,
+            Err(err) => Err(err),
+        };
+
+        match item {
+            Ok(Some(item)) => NotificationContent::from_item(message, item).send_signal_to_dart(),
+            Ok(None) => NotificationContent {
+                user_id: message.user_id,
+                room_id: message.room_id,
+                event_id: message.event_id,
+                found: false,
+                ..Default::default()
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("GetNotification: err {err:?}");
+                NotificationContent {
+                    user_id: message.user_id,
+                    room_id: message.room_id,
+                    event_id: message.event_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use matrix_sdk::ruma::{
+    matrix_uri::MatrixId, EventId, IdParseError, MatrixToUri, MatrixUri, OwnedRoomId,
+    OwnedServerName, RoomId,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::client::{ArcMatrixClients, MatrixClient},
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error("Only matrix.to links and matrix: URIs are supported.")]
+    UnsupportedUri,
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+/// Parses a `https://matrix.to/#/…` link or a `matrix:` URI.
+pub fn parse_uri(uri: &str) -> Result<UriTarget, Error> {
+    if uri.starts_with("matrix:") {
+        let uri = MatrixUri::parse(uri)?;
+        target(uri.id(), uri.via())
+    } else if uri.starts_with("https://matrix.to/") {
+        let uri = MatrixToUri::parse(uri)?;
+        target(uri.id(), uri.via())
+    } else {
+        Err(Error::UnsupportedUri)
+    }
+}
+
+fn target(id: &MatrixId, via: &[OwnedServerName]) -> Result<UriTarget, Error> {
+    let mut target = UriTarget {
+        via: via.iter().map(|server| server.to_string()).collect(),
+        ..Default::default()
+    };
+    match id {
+        MatrixId::User(user_id) => {
+            target.kind = UriTargetKind::User.into();
+            target.user_id = user_id.to_string();
+        }
+        MatrixId::Room(room_id) => {
+            target.kind = UriTargetKind::Room.into();
+            target.room_id = room_id.to_string();
+        }
+        MatrixId::RoomAlias(alias) => {
+            target.kind = UriTargetKind::RoomAlias.into();
+            target.room_alias = alias.to_string();
+        }
+        MatrixId::Event(room_id_or_alias, event_id) => {
+            target.kind = UriTargetKind::Event.into();
+            target.event_id = event_id.to_string();
+            match OwnedRoomId::try_from(room_id_or_alias.clone()) {
+                Ok(room_id) => target.room_id = room_id.to_string(),
+                Err(alias) => target.room_alias = alias.to_string(),
+            }
+        }
+        _ => return Err(Error::UnsupportedUri),
+    }
+    Ok(target)
+}
+
+/// A link to the room, or to one of its events.
+///
+/// The SDK picks the `via` servers from the members of the room: the server
+/// of the highest power level user, then the ones with the most members,
+/// leaving out the denied ones and IP addresses.
+pub async fn permalink(client: &MatrixClient, message: &GetPermalink) -> Result<String, Error> {
+    let room = client
+        .0
+        .get_room(&RoomId::parse(&message.room_id)?)
+        .ok_or(Error::RoomNotFound)?;
+    let permalink = match (message.event_id.as_str(), message.matrix_scheme) {
+        ("", false) => room.matrix_to_permalink().await?.to_string(),
+        ("", true) => room.matrix_permalink(false).await?.to_string(),
+        (event_id, false) => room
+            .matrix_to_event_permalink(EventId::parse(event_id)?)
+            .await?
+            .to_string(),
+        (event_id, true) => room
+            .matrix_event_permalink(EventId::parse(event_id)?)
+            .await?
+            .to_string(),
+    };
+    Ok(permalink)
+}
+
+async fn communicate_parse() {
+    let receiver = ParseMatrixUri::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ParseMatrixUri = dart_signal.message;
+        debug_print!("ParseMatrixUri: received {message:?}");
+
+        match parse_uri(&message.uri) {
+            Ok(target) => MatrixUriParsed {
+                uri: message.uri,
+                target: Some(target),
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("ParseMatrixUri: err {err:?}");
+                MatrixUriParsed {
+                    uri: message.uri,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_permalink(clients: ArcMatrixClients) {
+    let receiver = GetPermalink::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetPermalink = dart_signal.message;
+        debug_print!("GetPermalink: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => permalink(&client, &message).await,
+            None => Err(Error::MissingClient),
+        };
+
+        match result {
+            Ok(permalink) => PermalinkGenerated {
+                id: message.id,
+                room_id: message.room_id,
+                event_id: message.event_id,
+                permalink,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("GetPermalink: err {err:?}");
+                PermalinkGenerated {
+                    id: message.id,
+                    room_id: message.room_id,
+                    event_id: message.event_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(communicate_parse(), communicate_permalink(clients));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_uri;
+    use crate::messages::UriTargetKind;
+
+    #[test]
+    fn parse_matrix_to() {
+        let target = parse_uri("https://matrix.to/#/@alice:example.org").unwrap();
+        assert_eq!(target.kind(), UriTargetKind::User);
+        assert_eq!(target.user_id, "@alice:example.org");
+
+        let target =
+            parse_uri("https://matrix.to/#/%23room:example.org/$event?via=example.org").unwrap();
+        assert_eq!(target.kind(), UriTargetKind::Event);
+        assert_eq!(target.room_alias, "#room:example.org");
+        assert_eq!(target.event_id, "$event");
+        assert_eq!(target.via, ["example.org"]);
+    }
+
+    #[test]
+    fn parse_matrix_scheme() {
+        let target =
+            parse_uri("matrix:roomid/room:example.org?via=example.org&via=other.org").unwrap();
+        assert_eq!(target.kind(), UriTargetKind::Room);
+        assert_eq!(target.room_id, "!room:example.org");
+        assert_eq!(target.via, ["example.org", "other.org"]);
+
+        let target = parse_uri("matrix:r/room:example.org").unwrap();
+        assert_eq!(target.kind(), UriTargetKind::RoomAlias);
+        assert_eq!(target.room_alias, "#room:example.org");
+    }
+
+    #[test]
+    fn parse_unsupported() {
+        assert!(parse_uri("https://example.org/#/@alice:example.org").is_err());
+        assert!(parse_uri("https://matrix.to/#/alice").is_err());
+    }
+}
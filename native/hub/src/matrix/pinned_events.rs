@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use futures_util::StreamExt;
+use matrix_sdk::{
+    ruma::{
+        events::{
+            room::pinned_events::RoomPinnedEventsEventContent, AnySyncMessageLikeEvent,
+            AnySyncTimelineEvent, SyncMessageLikeEvent,
+        },
+        EventId, IdParseError, OwnedEventId, RoomId,
+    },
+    Room,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        subscriptions::Subscriptions,
+    },
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+fn room(client: &MatrixClient, room_id: &str) -> Result<Room, Error> {
+    client
+        .0
+        .get_room(&RoomId::parse(room_id)?)
+        .ok_or(Error::RoomNotFound)
+}
+
+/// Adds or removes an event from the pinned events of the room.
+///
+/// Does nothing if the event is already in the expected state.
+pub async fn set_pinned(room: &Room, event_id: &EventId, pin: bool) -> Result<(), Error> {
+    let mut pinned = room.pinned_event_ids().unwrap_or_default();
+    let is_pinned = pinned.iter().any(|id| id == event_id);
+    if pin == is_pinned {
+        return Ok(());
+    }
+
+    if pin {
+        pinned.push(event_id.to_owned());
+    } else {
+        pinned.retain(|id| id != event_id);
+    }
+    room.send_state_event(RoomPinnedEventsEventContent::new(pinned))
+        .await?;
+
+    Ok(())
+}
+
+/// Fetches and decrypts a pinned event for the banner.
+async fn resolve(room: &Room, event_id: &EventId) -> PinnedEvent {
+    let event = match room.event(event_id, None).await {
+        Ok(event) => event,
+        Err(err) => {
+            debug_print!("PinnedEvents: failed to fetch {event_id}: {err:?}");
+            return PinnedEvent {
+                event_id: event_id.to_string(),
+                error: err.to_string(),
+                ..Default::default()
+            };
+        }
+    };
+    let Ok(event) = event.raw().deserialize() else {
+        return PinnedEvent {
+            event_id: event_id.to_string(),
+            error: "The event is malformed.".to_string(),
+            ..Default::default()
+        };
+    };
+
+    let sender_name = room
+        .get_member_no_sync(event.sender())
+        .await
+        .ok()
+        .flatten()
+        .and_then(|member| member.display_name().map(ToOwned::to_owned))
+        .unwrap_or_default();
+    let (body, unable_to_decrypt) = match &event {
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(message),
+        )) => (message.content.body().to_owned(), false),
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(_)) => {
+            (Default::default(), true)
+        }
+        _ => Default::default(),
+    };
+
+    PinnedEvent {
+        event_id: event_id.to_string(),
+        event_type: event.event_type().to_string(),
+        sender: event.sender().to_string(),
+        sender_name,
+        body,
+        timestamp: event.origin_server_ts().get().into(),
+        unable_to_decrypt,
+        error: Default::default(),
+    }
+}
+
+/// Streams the pinned events of a room to Dart, resolving the ones that were
+/// pinned since the last update.
+///
+/// The ones that couldn't be decrypted are resolved again as room keys
+/// arrive for the room.
+async fn watch(id: String, room: Room) {
+    let mut resolved: HashMap<OwnedEventId, PinnedEvent> = HashMap::new();
+    let mut last: Option<Vec<OwnedEventId>> = None;
+    let mut info = room.subscribe_info();
+    let mut room_keys = room
+        .client()
+        .encryption()
+        .room_keys_received_stream()
+        .await
+        .map(Box::pin);
+    let mut retry = false;
+
+    loop {
+        let pinned = room.pinned_event_ids().unwrap_or_default();
+        if retry || last.as_ref() != Some(&pinned) {
+            // Events whose decryption failed get another try.
+            resolved.retain(|event_id, event| {
+                pinned.contains(event_id) && !event.unable_to_decrypt && event.error.is_empty()
+            });
+            let mut events = Vec::with_capacity(pinned.len());
+            for event_id in &pinned {
+                let event = match resolved.get(event_id) {
+                    Some(event) => event.clone(),
+                    None => {
+                        let event = resolve(&room, event_id).await;
+                        resolved.insert(event_id.clone(), event.clone());
+                        event
+                    }
+                };
+                events.push(event);
+            }
+
+            PinnedEventsUpdated {
+                id: id.clone(),
+                room_id: room.room_id().to_string(),
+                events,
+                error: Default::default(),
+            }
+            .send_signal_to_dart();
+            last = Some(pinned);
+        }
+
+        let next_keys = async {
+            match &mut room_keys {
+                Some(room_keys) => room_keys.next().await,
+                None => None,
+            }
+        };
+        tokio::select! {
+            update = info.next() => {
+                if update.is_none() {
+                    break;
+                }
+                retry = false;
+            }
+            Some(keys) = next_keys => {
+                // Keys missed while lagging may be for this room.
+                let for_room = keys.map_or(true, |keys| {
+                    keys.iter().any(|key| key.room_id == room.room_id())
+                });
+                retry = for_room && resolved.values().any(|event| event.unable_to_decrypt);
+            }
+        }
+    }
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+async fn communicate_subscribe(clients: ArcMatrixClients) {
+    let mut watched = Subscriptions::default();
+    let receiver = SubscribePinnedEvents::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SubscribePinnedEvents = dart_signal.message;
+        debug_print!("SubscribePinnedEvents: received {message:?}");
+
+        let key = (message.id.clone(), message.room_id.clone());
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => room(&client, &message.room_id).map(|room| (client, room)),
+            Err(err) => Err(err),
+        };
+        match result {
+            // Also on a subscription to the same room, so that it starts with
+            // the current list.
+            Ok((client, room)) => {
+                watched.start(key, &message.id, &client, watch(message.id.clone(), room))
+            }
+            Err(err) => {
+                watched.stop(&key);
+                debug_print!("SubscribePinnedEvents: err {err:?}");
+                PinnedEventsUpdated {
+                    id: message.id,
+                    room_id: message.room_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+/// The pinned events are streamed once the sync brings the change back.
+async fn communicate_pin(clients: ArcMatrixClients) {
+    let receiver = PinEvent::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: PinEvent = dart_signal.message;
+        debug_print!("PinEvent: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => match (
+                room(&client, &message.room_id),
+                EventId::parse(&message.event_id),
+            ) {
+                (Ok(room), Ok(event_id)) => set_pinned(&room, &event_id, message.pin).await,
+                (Err(err), _) => Err(err),
+                (_, Err(err)) => Err(err.into()),
+            },
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            debug_print!("PinEvent: err {err:?}");
+        }
+
+        EventPinned {
+            id: message.id,
+            room_id: message.room_id,
+            event_id: message.event_id,
+            pin: message.pin,
+            error: result.err().map(|err| err.to_string()).unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_subscribe(clients.clone()),
+        communicate_pin(clients),
+    );
+}
@@ -0,0 +1,319 @@
+use matrix_sdk::{
+    ruma::{
+        events::{
+            room::power_levels::{PowerLevelAction, RoomPowerLevels, RoomPowerLevelsEventContent},
+            MessageLikeEventType, StateEventType, TimelineEventType,
+        },
+        power_levels::NotificationPowerLevelType,
+        IdParseError, Int, RoomId, UserId,
+    },
+    Room,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::client::{ArcMatrixClients, MatrixClient},
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+impl PowerLevelRole {
+    /// The roles of the app, from `default_power_level_member.dart`, in
+    /// ascending order.
+    const PRESETS: [PowerLevelRole; 5] = [
+        PowerLevelRole::Guest,
+        PowerLevelRole::Member,
+        PowerLevelRole::Moderator,
+        PowerLevelRole::Admin,
+        PowerLevelRole::Owner,
+    ];
+
+    pub fn level(self) -> Option<i64> {
+        match self {
+            PowerLevelRole::Unspecified => None,
+            PowerLevelRole::Guest => Some(0),
+            PowerLevelRole::Member => Some(10),
+            PowerLevelRole::Moderator => Some(50),
+            PowerLevelRole::Admin => Some(80),
+            PowerLevelRole::Owner => Some(90),
+        }
+    }
+
+    /// The highest role the level reaches, like
+    /// `DefaultPowerLevelMember.getDefaultPowerLevelByUsersDefault`.
+    pub fn for_level(level: i64) -> Self {
+        Self::PRESETS
+            .into_iter()
+            .take_while(|role| role.level().is_some_and(|l| l <= level))
+            .last()
+            .unwrap_or(PowerLevelRole::Guest)
+    }
+}
+
+fn level(value: Int) -> i64 {
+    value.into()
+}
+
+impl PowerLevelsState {
+    fn new(id: String, room_id: String, power_levels: &RoomPowerLevels) -> Self {
+        Self {
+            id,
+            room_id,
+            users_default: level(power_levels.users_default),
+            users_default_role: PowerLevelRole::for_level(level(power_levels.users_default)).into(),
+            events_default: level(power_levels.events_default),
+            state_default: level(power_levels.state_default),
+            ban: level(power_levels.ban),
+            kick: level(power_levels.kick),
+            invite: level(power_levels.invite),
+            redact: level(power_levels.redact),
+            notifications_room: level(power_levels.notifications.room),
+            users: power_levels
+                .users
+                .iter()
+                .map(|(user_id, &value)| UserPowerLevel {
+                    user_id: user_id.to_string(),
+                    level: level(value),
+                    role: PowerLevelRole::for_level(level(value)).into(),
+                })
+                .collect(),
+            events: power_levels
+                .events
+                .iter()
+                .map(|(event_type, &value)| EventPowerLevel {
+                    event_type: event_type.to_string(),
+                    level: level(value),
+                })
+                .collect(),
+            error: Default::default(),
+        }
+    }
+}
+
+fn room(client: &MatrixClient, room_id: &str) -> Result<Room, Error> {
+    client
+        .0
+        .get_room(&RoomId::parse(room_id)?)
+        .ok_or(Error::RoomNotFound)
+}
+
+pub async fn can_user(client: &MatrixClient, message: &CheckPermission) -> Result<bool, Error> {
+    let power_levels = room(client, &message.room_id)?.power_levels().await?;
+    let user_id = UserId::parse(&message.user_id)?;
+    let event_type = message.event_type.as_str();
+
+    let action = match message.action() {
+        PermissionAction::Ban => PowerLevelAction::Ban,
+        PermissionAction::Kick => PowerLevelAction::Kick,
+        PermissionAction::Invite => PowerLevelAction::Invite,
+        PermissionAction::RedactOwn => PowerLevelAction::RedactOwn,
+        PermissionAction::RedactOther => PowerLevelAction::RedactOther,
+        PermissionAction::SendMessage => {
+            PowerLevelAction::SendMessage(MessageLikeEventType::from(event_type))
+        }
+        PermissionAction::SendState => {
+            PowerLevelAction::SendState(StateEventType::from(event_type))
+        }
+        PermissionAction::NotifyRoom => {
+            PowerLevelAction::TriggerNotification(NotificationPowerLevelType::Room)
+        }
+    };
+
+    Ok(power_levels.user_can_do(&user_id, action))
+}
+
+/// Sets the level of each user, either from their role or from a raw level.
+pub async fn set_user_levels(
+    client: &MatrixClient,
+    room_id: &str,
+    users: &[UserPowerLevel],
+) -> Result<RoomPowerLevels, Error> {
+    let room = room(client, room_id)?;
+    let mut content = RoomPowerLevelsEventContent::from(room.power_levels().await?);
+    for user in users {
+        let value = user.role().level().unwrap_or(user.level);
+        content
+            .users
+            .insert(UserId::parse(&user.user_id)?, Int::new_saturating(value));
+    }
+
+    send(&room, content).await
+}
+
+/// Sends the new levels and returns them, as the room only knows them once
+/// the sync brings them back.
+async fn send(room: &Room, content: RoomPowerLevelsEventContent) -> Result<RoomPowerLevels, Error> {
+    room.send_state_event(content.clone()).await?;
+    Ok(content.into())
+}
+
+/// Edits the levels required to send each event type, and the optional
+/// general levels.
+pub async fn set_required_levels(
+    client: &MatrixClient,
+    message: &SetRequiredPowerLevels,
+) -> Result<RoomPowerLevels, Error> {
+    let room = room(client, &message.room_id)?;
+    let mut content = RoomPowerLevelsEventContent::from(room.power_levels().await?);
+
+    for event in &message.events {
+        let event_type = TimelineEventType::from(event.event_type.as_str());
+        if event.remove {
+            content.events.remove(&event_type);
+        } else {
+            content
+                .events
+                .insert(event_type, Int::new_saturating(event.level));
+        }
+    }
+
+    let general = [
+        (&mut content.users_default, message.users_default),
+        (&mut content.events_default, message.events_default),
+        (&mut content.state_default, message.state_default),
+        (&mut content.ban, message.ban),
+        (&mut content.kick, message.kick),
+        (&mut content.invite, message.invite),
+        (&mut content.redact, message.redact),
+    ];
+    for (field, value) in general {
+        if let Some(value) = value {
+            *field = Int::new_saturating(value);
+        }
+    }
+
+    send(&room, content).await
+}
+
+fn reply(id: String, room_id: String, result: Result<RoomPowerLevels, Error>) {
+    match result {
+        Ok(power_levels) => PowerLevelsState::new(id, room_id, &power_levels).send_signal_to_dart(),
+        Err(err) => {
+            debug_print!("PowerLevels: err {err:?}");
+            PowerLevelsState {
+                id,
+                room_id,
+                error: err.to_string(),
+                ..Default::default()
+            }
+            .send_signal_to_dart();
+        }
+    }
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+async fn communicate_get(clients: ArcMatrixClients) {
+    let receiver = GetPowerLevels::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetPowerLevels = dart_signal.message;
+        debug_print!("GetPowerLevels: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => match room(&client, &message.room_id) {
+                Ok(room) => room.power_levels().await.map_err(Error::from),
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        reply(message.id, message.room_id, result);
+    }
+}
+
+async fn communicate_check(clients: ArcMatrixClients) {
+    let receiver = CheckPermission::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: CheckPermission = dart_signal.message;
+        debug_print!("CheckPermission: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => can_user(&client, &message).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            debug_print!("CheckPermission: err {err:?}");
+        }
+
+        PermissionChecked {
+            allowed: result.as_ref().is_ok_and(|allowed| *allowed),
+            error: result.err().map(|err| err.to_string()).unwrap_or_default(),
+            id: message.id,
+            room_id: message.room_id,
+            user_id: message.user_id,
+            action: message.action,
+            event_type: message.event_type,
+        }
+        .send_signal_to_dart();
+    }
+}
+
+async fn communicate_set_users(clients: ArcMatrixClients) {
+    let receiver = SetUserPowerLevels::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SetUserPowerLevels = dart_signal.message;
+        debug_print!("SetUserPowerLevels: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => set_user_levels(&client, &message.room_id, &message.users).await,
+            Err(err) => Err(err),
+        };
+        reply(message.id, message.room_id, result);
+    }
+}
+
+async fn communicate_set_required(clients: ArcMatrixClients) {
+    let receiver = SetRequiredPowerLevels::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SetRequiredPowerLevels = dart_signal.message;
+        debug_print!("SetRequiredPowerLevels: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => set_required_levels(&client, &message).await,
+            Err(err) => Err(err),
+        };
+        reply(message.id, message.room_id, result);
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_get(clients.clone()),
+        communicate_check(clients.clone()),
+        communicate_set_users(clients.clone()),
+        communicate_set_required(clients),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::messages::PowerLevelRole;
+
+    #[test]
+    fn role_for_level() {
+        assert_eq!(PowerLevelRole::for_level(-10), PowerLevelRole::Guest);
+        assert_eq!(PowerLevelRole::for_level(0), PowerLevelRole::Guest);
+        assert_eq!(PowerLevelRole::for_level(10), PowerLevelRole::Member);
+        assert_eq!(PowerLevelRole::for_level(49), PowerLevelRole::Member);
+        assert_eq!(PowerLevelRole::for_level(50), PowerLevelRole::Moderator);
+        assert_eq!(PowerLevelRole::for_level(80), PowerLevelRole::Admin);
+        assert_eq!(PowerLevelRole::for_level(100), PowerLevelRole::Owner);
+    }
+}
@@ -0,0 +1,261 @@
+use std::{sync::Arc, time::Duration};
+
+use matrix_sdk::{
+    ruma::{
+        api::client::state::get_state_events_for_key,
+        events::{
+            room::member::{MembershipState, OriginalSyncRoomMemberEvent, RoomMemberEventContent},
+            StateEventType,
+        },
+    },
+    Room,
+};
+use rinf::debug_print;
+use tokio::sync::Notify;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        media,
+        subscriptions::{HandlerGuard, Subscriptions},
+    },
+    messages::*,
+};
+
+/// The largest side of the uploaded avatars.
+const AVATAR_SIZE: u32 = 512;
+/// How long the member events of a profile change are awaited, so that the
+/// profile is fetched once for all the rooms.
+const CHANGE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] matrix_sdk::HttpError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Media(#[from] media::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("The client isn't logged in.")]
+    NotLoggedIn,
+    #[error("missing client")]
+    MissingClient,
+}
+
+pub async fn own_profile(client: &MatrixClient) -> Result<UserProfile, Error> {
+    let user_id = client.0.user_id().ok_or(Error::NotLoggedIn)?;
+    let response = client.0.account().fetch_user_profile().await?;
+    Ok(UserProfile {
+        user_id: user_id.to_string(),
+        display_name: response.displayname.unwrap_or_default(),
+        avatar_url: response
+            .avatar_url
+            .map(|url| url.to_string())
+            .unwrap_or_default(),
+    })
+}
+
+/// Updates the member event of the user in the room, if it doesn't match
+/// the profile.
+///
+/// The member event is read from the server, the store may not have the one
+/// the server updated itself yet. Only the profile of the event changes.
+async fn propagate(client: &MatrixClient, room: &Room, profile: &UserProfile) -> Result<(), Error> {
+    let user_id = client.0.user_id().ok_or(Error::NotLoggedIn)?;
+    let request = get_state_events_for_key::v3::Request::new(
+        room.room_id().to_owned(),
+        StateEventType::RoomMember,
+        user_id.to_string(),
+    );
+    let mut content: RoomMemberEventContent =
+        client.0.send(request).await?.content.deserialize_as()?;
+    if content.membership != MembershipState::Join {
+        return Ok(());
+    }
+    let display_name = Some(profile.display_name.clone()).filter(|name| !name.is_empty());
+    let avatar_url = Some(profile.avatar_url.as_str())
+        .filter(|url| !url.is_empty())
+        .map(Into::into);
+    if content.displayname == display_name && content.avatar_url == avatar_url {
+        return Ok(());
+    }
+
+    content.displayname = display_name;
+    content.avatar_url = avatar_url;
+    // Only valid on the join itself, it has to be signed by that server.
+    content.join_authorized_via_users_server = None;
+    content.reason = None;
+    room.send_state_event_for_key(user_id, content).await?;
+    Ok(())
+}
+
+/// Sets the display name and the avatar of the user.
+///
+/// Servers usually update the member events of the joined rooms themselves.
+/// With `propagate_to_rooms`, the rooms where they still don't match, like
+/// the ones with a name of their own, are updated too. Returns the rooms that
+/// couldn't be.
+pub async fn set_own_profile(
+    client: &MatrixClient,
+    mut message: SetOwnProfile,
+) -> Result<(UserProfile, Vec<String>), Error> {
+    let account = client.0.account();
+    if let Some(display_name) = &message.display_name {
+        let display_name = Some(display_name.as_str()).filter(|name| !name.is_empty());
+        account.set_display_name(display_name).await?;
+    }
+    if message.remove_avatar {
+        account.set_avatar_url(None).await?;
+    } else if !message.avatar.is_empty() {
+        let (avatar, content_type) =
+            media::resize_image(std::mem::take(&mut message.avatar), AVATAR_SIZE).await?;
+        let url = media::upload(client, &content_type, avatar).await?;
+        account.set_avatar_url(Some(&*url)).await?;
+    }
+
+    let profile = own_profile(client).await?;
+    let mut failed_room_ids = Vec::new();
+    if message.propagate_to_rooms {
+        for room in client.0.joined_rooms() {
+            if let Err(err) = propagate(client, &room, &profile).await {
+                debug_print!(
+                    "SetOwnProfile: failed to update {}: {err:?}",
+                    room.room_id()
+                );
+                failed_room_ids.push(room.room_id().to_string());
+            }
+        }
+    }
+    Ok((profile, failed_room_ids))
+}
+
+/// Streams the profile of the user, starting with the current one.
+///
+/// Changes, made from any device, show as member events of the user.
+async fn watch(id: String, client: MatrixClient) {
+    let changed = Arc::new(Notify::new());
+    let own_user_id = client.0.user_id().map(ToOwned::to_owned);
+    let notify = changed.clone();
+    let handle = client
+        .0
+        .add_event_handler(move |event: OriginalSyncRoomMemberEvent| {
+            let notify = notify.clone();
+            let own_user_id = own_user_id.clone();
+            async move {
+                if own_user_id.as_deref() != Some(event.state_key.as_ref()) {
+                    return;
+                }
+                let previous = event.unsigned.prev_content.as_ref();
+                let unchanged = previous.is_some_and(|previous| {
+                    previous.displayname == event.content.displayname
+                        && previous.avatar_url == event.content.avatar_url
+                });
+                if !unchanged {
+                    notify.notify_one();
+                }
+            }
+        });
+    let _handler = HandlerGuard(client.0.clone(), handle);
+
+    let mut last = None;
+    loop {
+        match own_profile(&client).await {
+            Ok(profile) if last.as_ref() != Some(&profile) => {
+                last = Some(profile.clone());
+                OwnProfileUpdated {
+                    id: id.clone(),
+                    profile: Some(profile),
+                    error: Default::default(),
+                }
+                .send_signal_to_dart();
+            }
+            Ok(_) => {}
+            Err(err) => debug_print!("SubscribeOwnProfile: err {err:?}"),
+        }
+        changed.notified().await;
+        tokio::time::sleep(CHANGE_DELAY).await;
+    }
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+async fn communicate_subscribe(clients: ArcMatrixClients) {
+    let mut watched = Subscriptions::default();
+    let receiver = SubscribeOwnProfile::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SubscribeOwnProfile = dart_signal.message;
+        debug_print!("SubscribeOwnProfile: received {message:?}");
+
+        match client(&clients, &message.id).await {
+            Ok(client) => {
+                let stream = watch(message.id.clone(), client.clone());
+                watched.start(message.id.clone(), &message.id, &client, stream);
+            }
+            Err(err) => {
+                watched.stop(&message.id);
+                debug_print!("SubscribeOwnProfile: err {err:?}");
+                OwnProfileUpdated {
+                    id: message.id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+/// The new profile also comes from the subscription, once the server applied
+/// it.
+async fn communicate_set(clients: ArcMatrixClients) {
+    let receiver = SetOwnProfile::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SetOwnProfile = dart_signal.message;
+        debug_print!(
+            "SetOwnProfile: received {} display name: {:?}",
+            message.id,
+            message.display_name
+        );
+
+        let id = message.id.clone();
+        let result = match client(&clients, &id).await {
+            Ok(client) => set_own_profile(&client, message).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok((profile, failed_room_ids)) => OwnProfileSet {
+                id,
+                profile: Some(profile),
+                failed_room_ids,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("SetOwnProfile: err {err:?}");
+                OwnProfileSet {
+                    id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_subscribe(clients.clone()),
+        communicate_set(clients),
+    );
+}
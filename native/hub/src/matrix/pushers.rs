@@ -0,0 +1,371 @@
+use matrix_sdk::{
+    ruma::{
+        api::client::push::{get_pushers, Pusher, PusherIds, PusherInit, PusherKind},
+        push::{HttpPusherData, PushFormat},
+    },
+    HttpError, StoreError,
+};
+use rinf::debug_print;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    matrix::client::{ArcMatrixClients, MatrixClient},
+    messages::*,
+};
+
+/// The key of the registrations made from this device in the state store.
+const PUSHERS_KEY: &[u8] = b"hub.pushers";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Http(#[from] HttpError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// A pusher registered from this device, kept so that it can be registered
+/// again when the push token rotates.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PusherConfig {
+    pub app_id: String,
+    pub pushkey: String,
+    pub app_display_name: String,
+    pub device_display_name: String,
+    pub lang: String,
+    pub gateway_url: String,
+    pub event_id_only: bool,
+    pub profile_tag: Option<String>,
+    /// The pushkey is a UnifiedPush endpoint. `gateway_url` is then only used
+    /// when the distributor doesn't provide a Matrix gateway.
+    pub unified_push: bool,
+}
+
+impl PusherConfig {
+    fn ids(&self) -> PusherIds {
+        PusherIds::new(self.pushkey.clone(), self.app_id.clone())
+    }
+
+    fn into_pusher(self, gateway_url: String) -> Pusher {
+        let mut data = HttpPusherData::new(gateway_url);
+        if self.event_id_only {
+            data.format = Some(PushFormat::EventIdOnly);
+        }
+
+        PusherInit {
+            ids: self.ids(),
+            kind: PusherKind::Http(data),
+            app_display_name: self.app_display_name,
+            device_display_name: self.device_display_name,
+            profile_tag: self.profile_tag,
+            lang: self.lang,
+        }
+        .into()
+    }
+}
+
+impl From<RegisterPusher> for PusherConfig {
+    fn from(value: RegisterPusher) -> Self {
+        Self {
+            app_id: value.app_id,
+            pushkey: value.pushkey,
+            app_display_name: value.app_display_name,
+            device_display_name: value.device_display_name,
+            lang: value.lang,
+            gateway_url: value.gateway_url,
+            event_id_only: value.event_id_only,
+            profile_tag: Some(value.profile_tag).filter(|t| !t.is_empty()),
+            unified_push: value.unified_push,
+        }
+    }
+}
+
+/// Finds the Matrix gateway of a UnifiedPush distributor, as described in
+/// <https://unifiedpush.org/spec/gateway/>.
+async fn unified_push_gateway(client: &MatrixClient, endpoint: &str) -> Option<String> {
+    let mut url = Url::parse(endpoint).ok()?;
+    url.set_path("/_matrix/push/v1/notify");
+    url.set_query(None);
+
+    let response = client.0.http_client().get(url.clone()).send().await.ok()?;
+    let body: serde_json::Value = serde_json::from_slice(&response.bytes().await.ok()?).ok()?;
+
+    (body["unifiedpush"]["gateway"] == "matrix").then(|| url.into())
+}
+
+async fn stored_pushers(client: &MatrixClient) -> Result<Vec<PusherConfig>, Error> {
+    match client.0.state_store().get_custom_value(PUSHERS_KEY).await? {
+        Some(json) => Ok(serde_json::from_slice(&json)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn store_pushers(client: &MatrixClient, pushers: &[PusherConfig]) -> Result<(), Error> {
+    client
+        .0
+        .state_store()
+        .set_custom_value(PUSHERS_KEY, serde_json::to_vec(pushers)?)
+        .await?;
+    Ok(())
+}
+
+pub async fn register_pusher(client: &MatrixClient, config: PusherConfig) -> Result<(), Error> {
+    let gateway_url = match config.unified_push {
+        true => unified_push_gateway(client, &config.pushkey)
+            .await
+            .unwrap_or_else(|| config.gateway_url.clone()),
+        false => config.gateway_url.clone(),
+    };
+    client
+        .0
+        .pusher()
+        .set(config.clone().into_pusher(gateway_url))
+        .await?;
+
+    let mut pushers = stored_pushers(client).await?;
+    pushers.retain(|p| !(p.app_id == config.app_id && p.pushkey == config.pushkey));
+    pushers.push(config);
+    store_pushers(client, &pushers).await
+}
+
+pub async fn list_pushers(client: &MatrixClient) -> Result<Vec<PusherInfo>, Error> {
+    let stored = stored_pushers(client).await?;
+    let response = client.0.send(get_pushers::v3::Request::new()).await?;
+
+    Ok(response
+        .pushers
+        .into_iter()
+        .map(|pusher| {
+            let (kind, gateway_url, event_id_only) = match &pusher.kind {
+                PusherKind::Http(data) => (
+                    "http",
+                    data.url.clone(),
+                    data.format == Some(PushFormat::EventIdOnly),
+                ),
+                PusherKind::Email(_) => ("email", String::new(), false),
+                _ => ("unknown", String::new(), false),
+            };
+            PusherInfo {
+                registered_here: stored
+                    .iter()
+                    .any(|p| p.app_id == pusher.ids.app_id && p.pushkey == pusher.ids.pushkey),
+                app_id: pusher.ids.app_id,
+                pushkey: pusher.ids.pushkey,
+                kind: kind.to_string(),
+                app_display_name: pusher.app_display_name,
+                device_display_name: pusher.device_display_name,
+                lang: pusher.lang,
+                gateway_url,
+                event_id_only,
+                profile_tag: pusher.profile_tag.unwrap_or_default(),
+            }
+        })
+        .collect())
+}
+
+/// Removes the pusher from the server, then from the registrations made from
+/// this device, so that a failure keeps it known.
+pub async fn remove_pusher(
+    client: &MatrixClient,
+    app_id: String,
+    pushkey: String,
+) -> Result<(), Error> {
+    client
+        .0
+        .pusher()
+        .delete(PusherIds::new(pushkey.clone(), app_id.clone()))
+        .await?;
+
+    let mut pushers = stored_pushers(client).await?;
+    pushers.retain(|p| !(p.app_id == app_id && p.pushkey == pushkey));
+    store_pushers(client, &pushers).await
+}
+
+/// Registers again the pusher of `app_id` registered from this device with
+/// the new push token, and removes the ones using an old token.
+///
+/// There's a single pusher per app id and token, the latest registration is
+/// the one moved to the new token. Once it's registered, a failure can be
+/// retried, the old tokens left are removed then.
+///
+/// Returns the number of old tokens that were replaced.
+pub async fn rotate_push_token(
+    client: &MatrixClient,
+    app_id: String,
+    new_pushkey: String,
+) -> Result<usize, Error> {
+    let previous: Vec<PusherConfig> = stored_pushers(client)
+        .await?
+        .into_iter()
+        .filter(|p| p.app_id == app_id)
+        .collect();
+    let rotated = previous.iter().find(|p| p.pushkey == new_pushkey);
+    let Some(config) = rotated.or(previous.last()) else {
+        return Ok(0);
+    };
+
+    register_pusher(
+        client,
+        PusherConfig {
+            pushkey: new_pushkey.clone(),
+            ..config.clone()
+        },
+    )
+    .await?;
+
+    let mut replaced = 0;
+    for config in &previous {
+        if config.pushkey != new_pushkey {
+            remove_pusher(client, config.app_id.clone(), config.pushkey.clone()).await?;
+            replaced += 1;
+        }
+    }
+
+    Ok(replaced)
+}
+
+async fn communicate_register(clients: ArcMatrixClients) {
+    let receiver = RegisterPusher::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: RegisterPusher = dart_signal.message;
+        // The pushkey is the push token, it isn't logged.
+        debug_print!(
+            "RegisterPusher: received {} app: {}",
+            message.id,
+            message.app_id
+        );
+
+        let id = message.id.clone();
+        let app_id = message.app_id.clone();
+        let pushkey = message.pushkey.clone();
+        let client = clients.lock().await.get(&id).cloned();
+        let result = match client {
+            Some(client) => register_pusher(&client, message.into())
+                .await
+                .map_err(|err| err.to_string()),
+            None => Err("missing client".to_string()),
+        };
+        if let Err(err) = &result {
+            debug_print!("RegisterPusher: err {err}");
+        }
+
+        PusherRegistered {
+            id,
+            app_id,
+            pushkey,
+            error: result.err().unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+async fn communicate_list(clients: ArcMatrixClients) {
+    let receiver = ListPushers::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ListPushers = dart_signal.message;
+        debug_print!("ListPushers: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => list_pushers(&client).await.map_err(|err| err.to_string()),
+            None => Err("missing client".to_string()),
+        };
+
+        match result {
+            Ok(pushers) => PushersList {
+                id: message.id,
+                pushers,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("ListPushers: err {err}");
+                PushersList {
+                    id: message.id,
+                    pushers: Default::default(),
+                    error: err,
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_remove(clients: ArcMatrixClients) {
+    let receiver = RemovePusher::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: RemovePusher = dart_signal.message;
+        debug_print!(
+            "RemovePusher: received {} app: {}",
+            message.id,
+            message.app_id
+        );
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => remove_pusher(&client, message.app_id.clone(), message.pushkey.clone())
+                .await
+                .map_err(|err| err.to_string()),
+            None => Err("missing client".to_string()),
+        };
+        if let Err(err) = &result {
+            debug_print!("RemovePusher: err {err}");
+        }
+
+        PusherRemoved {
+            id: message.id,
+            app_id: message.app_id,
+            pushkey: message.pushkey,
+            error: result.err().unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+async fn communicate_rotate(clients: ArcMatrixClients) {
+    let receiver = RotatePushToken::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: RotatePushToken = dart_signal.message;
+        debug_print!(
+            "RotatePushToken: received {} app: {}",
+            message.id,
+            message.app_id
+        );
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => {
+                rotate_push_token(&client, message.app_id.clone(), message.pushkey.clone())
+                    .await
+                    .map_err(|err| err.to_string())
+            }
+            None => Err("missing client".to_string()),
+        };
+        if let Err(err) = &result {
+            debug_print!("RotatePushToken: err {err}");
+        }
+
+        PushTokenRotated {
+            id: message.id,
+            app_id: message.app_id,
+            pushkey: message.pushkey,
+            reregistered: result.as_ref().map(|n| *n as u32).unwrap_or_default(),
+            error: result.err().unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_register(clients.clone()),
+        communicate_list(clients.clone()),
+        communicate_remove(clients.clone()),
+        communicate_rotate(clients),
+    );
+}
@@ -0,0 +1,175 @@
+use matrix_sdk::SessionMeta;
+use rinf::debug_print;
+use url::Url;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient, OidcConfiguration, OidcError, OidcPrompt},
+        session::SessionStore,
+    },
+    messages::*,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Oidc(OidcError),
+    Login(matrix_sdk::Error),
+    MissingClient,
+    NotSoftLoggedOut,
+}
+
+impl Error {
+    fn message(self) -> String {
+        match self {
+            Error::Oidc(err) => err.to_string(),
+            Error::Login(err) => err.to_string(),
+            Error::MissingClient => "missing client".to_string(),
+            Error::NotSoftLoggedOut => "client has no session to log into again".to_string(),
+        }
+    }
+}
+
+/// The soft logged out client, along with its session.
+///
+/// It's logged into again in place: its stores stay open and logging in with
+/// the same device id keeps the encryption keys.
+async fn soft_logged_out_client(
+    clients: &ArcMatrixClients,
+    id: &str,
+) -> Result<(MatrixClient, SessionMeta), Error> {
+    let client = clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)?;
+    let meta = client
+        .0
+        .session_meta()
+        .cloned()
+        .ok_or(Error::NotSoftLoggedOut)?;
+    Ok((client, meta))
+}
+
+pub async fn reauthenticate_with_oidc(
+    clients: &ArcMatrixClients,
+    id: &str,
+    oidc_configuration: &OidcConfiguration,
+) -> Result<Url, Error> {
+    let (client, meta) = soft_logged_out_client(clients, id).await?;
+    // The authorization server replaces the tokens of the expired session as
+    // long as the user logs into the same account.
+    client
+        .url_for_oidc(
+            oidc_configuration,
+            Some(OidcPrompt::Login),
+            Some(format!("mxid:{}", meta.user_id)),
+            Some(meta.device_id.to_string()),
+        )
+        .await
+        .map(|data| data.url)
+        .map_err(Error::Oidc)
+}
+
+pub async fn reauthenticate_with_password(
+    clients: &ArcMatrixClients,
+    id: &str,
+    password: &str,
+) -> Result<MatrixClient, Error> {
+    let (client, meta) = soft_logged_out_client(clients, id).await?;
+    client
+        .0
+        .matrix_auth()
+        .login_username(&meta.user_id, password)
+        .device_id(meta.device_id.as_str())
+        .send()
+        .await
+        .map_err(Error::Login)?;
+
+    Ok(client)
+}
+
+async fn communicate_oidc(clients: ArcMatrixClients) {
+    let receiver = ReauthenticateWithOidc::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ReauthenticateWithOidc = dart_signal.message;
+        debug_print!("ReauthenticateWithOidc: received {message:?}");
+
+        let oidc_configuration = OidcConfiguration {
+            client_name: Some(message.client_name),
+            redirect_uri: message.redirect_uri,
+            client_uri: message.client_uri,
+            logo_uri: Some(message.logo_uri),
+            tos_uri: Some(message.tos_uri),
+            policy_uri: Some(message.policy_uri),
+            static_registrations: Default::default(),
+        };
+
+        match reauthenticate_with_oidc(&clients, &message.id, &oidc_configuration).await {
+            // The login is completed by `JustFinishSso` with the same id.
+            Ok(url) => {
+                debug_print!("ReauthenticateWithOidc: ok {url:?}");
+                ReauthenticationUrl {
+                    id: message.id,
+                    url: url.into(),
+                    error: Default::default(),
+                }
+                .send_signal_to_dart();
+            }
+            Err(err) => {
+                debug_print!("ReauthenticateWithOidc: err {err:?}");
+                ReauthenticationUrl {
+                    id: message.id,
+                    url: Default::default(),
+                    error: err.message(),
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_password(clients: ArcMatrixClients, sessions: SessionStore) {
+    let receiver = ReauthenticateWithPassword::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ReauthenticateWithPassword = dart_signal.message;
+        debug_print!("ReauthenticateWithPassword: received {}", message.id);
+
+        match reauthenticate_with_password(&clients, &message.id, &message.password).await {
+            Ok(client) => {
+                debug_print!("ReauthenticateWithPassword: ok");
+                let tokens = client.0.session_tokens();
+                sessions
+                    .track(clients.clone(), message.id.clone(), client)
+                    .await;
+                Reauthenticated {
+                    id: message.id,
+                    access_token: tokens
+                        .as_ref()
+                        .map(|t| t.access_token.clone())
+                        .unwrap_or_default(),
+                    refresh_token: tokens.and_then(|t| t.refresh_token).unwrap_or_default(),
+                    error: Default::default(),
+                }
+                .send_signal_to_dart();
+            }
+            Err(err) => {
+                debug_print!("ReauthenticateWithPassword: err {err:?}");
+                Reauthenticated {
+                    id: message.id,
+                    access_token: Default::default(),
+                    refresh_token: Default::default(),
+                    error: err.message(),
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients, sessions: SessionStore) {
+    tokio::join!(
+        communicate_oidc(clients.clone()),
+        communicate_password(clients, sessions),
+    );
+}
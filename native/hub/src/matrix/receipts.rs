@@ -0,0 +1,102 @@
+use matrix_sdk::{
+    room::Receipts,
+    ruma::{
+        events::receipt::{ReceiptThread, ReceiptType},
+        EventId, IdParseError, RoomId,
+    },
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::client::{ArcMatrixClients, MatrixClient},
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error("The fully read marker can't be set in a thread.")]
+    FullyReadInThread,
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+/// Moves the read receipts and the fully read marker to the event.
+///
+/// The SDK doesn't send receipts that would move backwards. The unread counts
+/// of the room list are updated once the receipts come back from the sync.
+pub async fn send_receipts(client: &MatrixClient, message: &SendReceipts) -> Result<(), Error> {
+    let room = client
+        .0
+        .get_room(&RoomId::parse(&message.room_id)?)
+        .ok_or(Error::RoomNotFound)?;
+    let event_id = EventId::parse(&message.event_id)?;
+
+    if !message.thread_root_id.is_empty() {
+        if message.fully_read {
+            return Err(Error::FullyReadInThread);
+        }
+        let receipt_type = if message.private {
+            ReceiptType::ReadPrivate
+        } else {
+            ReceiptType::Read
+        };
+        if message.read {
+            let thread = ReceiptThread::Thread(EventId::parse(&message.thread_root_id)?);
+            room.send_single_receipt(receipt_type, thread, event_id)
+                .await?;
+        }
+        return Ok(());
+    }
+
+    let mut receipts = Receipts::new();
+    if message.read {
+        receipts = if message.private {
+            receipts.private_read_receipt(event_id.clone())
+        } else {
+            receipts.public_read_receipt(event_id.clone())
+        };
+    }
+    if message.fully_read {
+        receipts = receipts.fully_read_marker(event_id);
+    }
+    room.send_multiple_receipts(receipts).await?;
+
+    // Reading a room takes the mark set by "mark as unread" off.
+    if message.read && room.is_marked_unread() {
+        room.set_unread_flag(false).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    let receiver = SendReceipts::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SendReceipts = dart_signal.message;
+        debug_print!("SendReceipts: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => send_receipts(&client, &message).await,
+            None => Err(Error::MissingClient),
+        };
+        if let Err(err) = &result {
+            debug_print!("SendReceipts: err {err:?}");
+        }
+
+        ReceiptsSent {
+            id: message.id,
+            room_id: message.room_id,
+            event_id: message.event_id,
+            thread_root_id: message.thread_root_id,
+            error: result.err().map(|err| err.to_string()).unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
@@ -0,0 +1,245 @@
+use matrix_sdk::{
+    ruma::{
+        events::{AnyGlobalAccountDataEvent, GlobalAccountDataEventType},
+        serde::Raw,
+    },
+    Client, StateChanges, StoreError,
+};
+use rinf::debug_print;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    matrix::{
+        client::ArcMatrixClients,
+        subscriptions::{HandlerGuard, Subscriptions},
+    },
+    messages::*,
+};
+
+/// The account data shared with Element.
+const EVENT_TYPE: &str = "io.element.recent_emoji";
+/// How many emoji are kept, like Element.
+const STORAGE_LIMIT: usize = 100;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("missing client")]
+    MissingClient,
+}
+
+/// The content of `io.element.recent_emoji`: emoji and how many times they
+/// were used, most recently used first.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecentEmojiContent {
+    #[serde(default)]
+    pub recent_emoji: Vec<(String, u64)>,
+}
+
+impl RecentEmojiContent {
+    /// Moves the emoji to the front and counts one more use.
+    pub fn record(&mut self, emoji: &str) {
+        let count = match self.recent_emoji.iter().position(|(e, _)| e == emoji) {
+            Some(index) => self.recent_emoji.remove(index).1,
+            None => 0,
+        };
+        self.recent_emoji.insert(0, (emoji.to_owned(), count + 1));
+        self.recent_emoji.truncate(STORAGE_LIMIT);
+    }
+
+    /// The most used emoji first, the most recently used first on ties.
+    pub fn ranked(&self) -> Vec<RecentEmoji> {
+        let mut ranked = self.recent_emoji.clone();
+        ranked.sort_by(|(_, a), (_, b)| b.cmp(a));
+        ranked
+            .into_iter()
+            .map(|(emoji, count)| RecentEmoji { emoji, count })
+            .collect()
+    }
+}
+
+fn event_type() -> GlobalAccountDataEventType {
+    GlobalAccountDataEventType::from(EVENT_TYPE)
+}
+
+/// The recent emoji as of the last sync.
+pub async fn load(client: &Client) -> Result<RecentEmojiContent, Error> {
+    let Some(event) = client
+        .state_store()
+        .get_account_data_event(event_type())
+        .await?
+    else {
+        return Ok(Default::default());
+    };
+
+    Ok(event
+        .get_field::<RecentEmojiContent>("content")?
+        .unwrap_or_default())
+}
+
+/// Records a use of the emoji, for example when reacting with it.
+///
+/// The new list is stored right away rather than when the sync brings it
+/// back, so that uses recorded in a row all count.
+pub async fn record(client: &Client, emoji: &str) -> Result<RecentEmojiContent, Error> {
+    let mut content = load(client).await?;
+    content.record(emoji);
+
+    let raw = Raw::from_json(serde_json::value::to_raw_value(&content)?);
+    client
+        .account()
+        .set_account_data_raw(event_type(), raw)
+        .await?;
+
+    let event = serde_json::json!({ "type": EVENT_TYPE, "content": &content });
+    let mut changes = StateChanges::default();
+    changes.account_data.insert(
+        event_type(),
+        Raw::from_json(serde_json::value::to_raw_value(&event)?),
+    );
+    client.state_store().save_changes(&changes).await?;
+
+    Ok(content)
+}
+
+/// Streams the list to Dart when another device changes it.
+async fn watch(id: String, client: Client) {
+    let handle = client.add_event_handler(move |event: Raw<AnyGlobalAccountDataEvent>| {
+        let id = id.clone();
+        async move {
+            if event.get_field::<String>("type").ok().flatten().as_deref() != Some(EVENT_TYPE) {
+                return;
+            }
+            let content = event
+                .get_field::<RecentEmojiContent>("content")
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            RecentEmojiList {
+                id,
+                emoji: content.ranked(),
+                error: Default::default(),
+            }
+            .send_signal_to_dart();
+        }
+    });
+    let _handler = HandlerGuard(client, handle);
+    std::future::pending::<()>().await;
+}
+
+fn reply(id: String, result: Result<RecentEmojiContent, Error>) {
+    match result {
+        Ok(content) => RecentEmojiList {
+            id,
+            emoji: content.ranked(),
+            error: Default::default(),
+        }
+        .send_signal_to_dart(),
+        Err(err) => {
+            debug_print!("RecentEmoji: err {err:?}");
+            RecentEmojiList {
+                id,
+                error: err.to_string(),
+                ..Default::default()
+            }
+            .send_signal_to_dart();
+        }
+    }
+}
+
+async fn communicate_get(clients: ArcMatrixClients) {
+    let mut watched = Subscriptions::default();
+    let receiver = GetRecentEmoji::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetRecentEmoji = dart_signal.message;
+        debug_print!("GetRecentEmoji: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => {
+                let stream = watch(message.id.clone(), client.0.clone());
+                watched.start(message.id.clone(), &message.id, &client, stream);
+                load(&client.0).await
+            }
+            None => {
+                watched.stop(&message.id);
+                Err(Error::MissingClient)
+            }
+        };
+        reply(message.id, result);
+    }
+}
+
+async fn communicate_record(clients: ArcMatrixClients) {
+    let receiver = RecordEmoji::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: RecordEmoji = dart_signal.message;
+        debug_print!("RecordEmoji: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => record(&client.0, &message.emoji).await,
+            None => Err(Error::MissingClient),
+        };
+        reply(message.id, result);
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_get(clients.clone()),
+        communicate_record(clients),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecentEmojiContent;
+
+    #[test]
+    fn record_moves_to_front() {
+        let mut content = RecentEmojiContent::default();
+        content.record("👍");
+        content.record("😀");
+        content.record("👍");
+
+        assert_eq!(
+            content.recent_emoji,
+            vec![("👍".to_owned(), 2), ("😀".to_owned(), 1)]
+        );
+    }
+
+    #[test]
+    fn ranked_by_count_then_recency() {
+        let content = RecentEmojiContent {
+            recent_emoji: vec![
+                ("🎉".to_owned(), 1),
+                ("😀".to_owned(), 3),
+                ("👍".to_owned(), 1),
+            ],
+        };
+
+        let ranked: Vec<String> = content
+            .ranked()
+            .into_iter()
+            .map(|recent| recent.emoji)
+            .collect();
+        assert_eq!(ranked, vec!["😀", "🎉", "👍"]);
+    }
+
+    #[test]
+    fn parses_element_content() {
+        let content: RecentEmojiContent =
+            serde_json::from_str(r#"{"recent_emoji":[["😀",2],["👍",1]]}"#).unwrap();
+
+        assert_eq!(
+            content.recent_emoji,
+            vec![("😀".to_owned(), 2), ("👍".to_owned(), 1)]
+        );
+    }
+}
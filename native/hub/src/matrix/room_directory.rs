@@ -0,0 +1,332 @@
+use matrix_sdk::{
+    room_preview::RoomPreview as SdkRoomPreview,
+    ruma::{
+        api::client::directory::get_public_rooms_filtered::v3::Request as PublicRoomsRequest,
+        directory::{Filter, PublicRoomJoinRule, PublicRoomsChunk, RoomNetwork},
+        room::{JoinRuleSummary, RoomType},
+        IdParseError, OwnedServerName, RoomAliasId, RoomOrAliasId, ServerName, UInt,
+    },
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::client::{ArcMatrixClients, MatrixClient},
+    messages::*,
+};
+
+const DEFAULT_LIMIT: u32 = 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Http(#[from] matrix_sdk::HttpError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error("missing client")]
+    MissingClient,
+}
+
+fn servers(servers: &[String]) -> Result<Vec<OwnedServerName>, Error> {
+    Ok(servers
+        .iter()
+        .map(ServerName::parse)
+        .collect::<Result<_, _>>()?)
+}
+
+impl From<PublicRoomsChunk> for RoomPreview {
+    fn from(value: PublicRoomsChunk) -> Self {
+        let join_rule = match value.join_rule {
+            PublicRoomJoinRule::Public => RoomJoinRule::Public,
+            PublicRoomJoinRule::Knock => RoomJoinRule::Knock,
+            _ => RoomJoinRule::Unspecified,
+        };
+        Self {
+            room_id: value.room_id.to_string(),
+            name: value.name.unwrap_or_default(),
+            topic: value.topic.unwrap_or_default(),
+            avatar_url: value
+                .avatar_url
+                .map(|url| url.to_string())
+                .unwrap_or_default(),
+            canonical_alias: value
+                .canonical_alias
+                .map(|alias| alias.to_string())
+                .unwrap_or_default(),
+            member_count: value.num_joined_members.into(),
+            join_rule: join_rule.into(),
+            world_readable: value.world_readable,
+            guest_can_join: Some(value.guest_can_join),
+            is_space: value.room_type == Some(RoomType::Space),
+            membership: None,
+        }
+    }
+}
+
+impl From<SdkRoomPreview> for RoomPreview {
+    fn from(value: SdkRoomPreview) -> Self {
+        let join_rule = match value.join_rule {
+            Some(JoinRuleSummary::Public) => RoomJoinRule::Public,
+            Some(JoinRuleSummary::Invite) => RoomJoinRule::Invite,
+            Some(JoinRuleSummary::Knock) => RoomJoinRule::Knock,
+            Some(JoinRuleSummary::Restricted(_) | JoinRuleSummary::KnockRestricted(_)) => {
+                RoomJoinRule::Restricted
+            }
+            _ => RoomJoinRule::Unspecified,
+        };
+        Self {
+            room_id: value.room_id.to_string(),
+            name: value.name.unwrap_or_default(),
+            topic: value.topic.unwrap_or_default(),
+            avatar_url: value
+                .avatar_url
+                .map(|url| url.to_string())
+                .unwrap_or_default(),
+            canonical_alias: value
+                .canonical_alias
+                .map(|alias| alias.to_string())
+                .unwrap_or_default(),
+            member_count: value.num_joined_members,
+            join_rule: join_rule.into(),
+            world_readable: value.is_world_readable.unwrap_or_default(),
+            guest_can_join: None,
+            is_space: value.room_type == Some(RoomType::Space),
+            membership: value.state.map(|state| RoomMembership::from(state).into()),
+        }
+    }
+}
+
+/// Lists the public rooms of the server of the user, or of `message.server`.
+///
+/// Returns the rooms, the tokens of the next and previous pages and the
+/// estimated number of rooms.
+pub async fn public_rooms(
+    client: &MatrixClient,
+    message: &ListPublicRooms,
+) -> Result<(Vec<RoomPreview>, String, String, Option<u64>), Error> {
+    let mut request = PublicRoomsRequest::new();
+    if !message.server.is_empty() {
+        request.server = Some(ServerName::parse(&message.server)?);
+    }
+    request.limit = Some(UInt::from(match message.limit {
+        0 => DEFAULT_LIMIT,
+        limit => limit,
+    }));
+    request.since = Some(message.since.clone()).filter(|since| !since.is_empty());
+
+    let mut filter = Filter::new();
+    filter.generic_search_term = Some(message.search_term.clone()).filter(|term| !term.is_empty());
+    request.filter = filter;
+    request.room_network = if !message.third_party_instance_id.is_empty() {
+        RoomNetwork::ThirdParty(message.third_party_instance_id.clone())
+    } else if message.include_all_networks {
+        RoomNetwork::All
+    } else {
+        RoomNetwork::Matrix
+    };
+
+    let response = client.0.public_rooms_filtered(request).await?;
+    Ok((
+        response.chunk.into_iter().map(Into::into).collect(),
+        response.next_batch.unwrap_or_default(),
+        response.prev_batch.unwrap_or_default(),
+        response.total_room_count_estimate.map(Into::into),
+    ))
+}
+
+/// The preview of a room the user may not be in, for the join dialog.
+pub async fn preview(
+    client: &MatrixClient,
+    room_id_or_alias: &str,
+    via: &[String],
+) -> Result<RoomPreview, Error> {
+    let room_id_or_alias = RoomOrAliasId::parse(room_id_or_alias)?;
+    let preview = client
+        .0
+        .get_room_preview(&room_id_or_alias, servers(via)?)
+        .await?;
+    Ok(preview.into())
+}
+
+/// Joins a room, `via` giving the servers to join through when the server of
+/// the user isn't in it yet.
+pub async fn join(
+    client: &MatrixClient,
+    room_id_or_alias: &str,
+    via: &[String],
+) -> Result<String, Error> {
+    let room_id_or_alias = RoomOrAliasId::parse(room_id_or_alias)?;
+    let room = client
+        .0
+        .join_room_by_id_or_alias(&room_id_or_alias, &servers(via)?)
+        .await?;
+    Ok(room.room_id().to_string())
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+async fn communicate_list(clients: ArcMatrixClients) {
+    let receiver = ListPublicRooms::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ListPublicRooms = dart_signal.message;
+        debug_print!("ListPublicRooms: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => public_rooms(&client, &message).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok((rooms, next_batch, prev_batch, total_count_estimate)) => PublicRoomsList {
+                id: message.id,
+                server: message.server,
+                search_term: message.search_term,
+                rooms,
+                next_batch,
+                prev_batch,
+                total_count_estimate,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("ListPublicRooms: err {err:?}");
+                PublicRoomsList {
+                    id: message.id,
+                    server: message.server,
+                    search_term: message.search_term,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_resolve(clients: ArcMatrixClients) {
+    let receiver = ResolveRoomAlias::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ResolveRoomAlias = dart_signal.message;
+        debug_print!("ResolveRoomAlias: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => match RoomAliasId::parse(&message.alias) {
+                Ok(alias) => client
+                    .0
+                    .resolve_room_alias(&alias)
+                    .await
+                    .map_err(Error::from),
+                Err(err) => Err(err.into()),
+            },
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(response) => RoomAliasResolved {
+                id: message.id,
+                alias: message.alias,
+                room_id: response.room_id.to_string(),
+                servers: response
+                    .servers
+                    .into_iter()
+                    .map(|server| server.to_string())
+                    .collect(),
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("ResolveRoomAlias: err {err:?}");
+                RoomAliasResolved {
+                    id: message.id,
+                    alias: message.alias,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_preview(clients: ArcMatrixClients) {
+    let receiver = GetRoomPreview::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetRoomPreview = dart_signal.message;
+        debug_print!("GetRoomPreview: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => preview(&client, &message.room_id_or_alias, &message.via).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(preview) => RoomPreviewFetched {
+                id: message.id,
+                room_id_or_alias: message.room_id_or_alias,
+                preview: Some(preview),
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("GetRoomPreview: err {err:?}");
+                RoomPreviewFetched {
+                    id: message.id,
+                    room_id_or_alias: message.room_id_or_alias,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_join(clients: ArcMatrixClients) {
+    let receiver = JoinRoom::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: JoinRoom = dart_signal.message;
+        debug_print!("JoinRoom: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => join(&client, &message.room_id_or_alias, &message.via).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(room_id) => RoomJoined {
+                id: message.id,
+                room_id_or_alias: message.room_id_or_alias,
+                room_id,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("JoinRoom: err {err:?}");
+                RoomJoined {
+                    id: message.id,
+                    room_id_or_alias: message.room_id_or_alias,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_list(clients.clone()),
+        communicate_resolve(clients.clone()),
+        communicate_preview(clients.clone()),
+        communicate_join(clients),
+    );
+}
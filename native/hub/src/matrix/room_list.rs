@@ -0,0 +1,139 @@
+use std::collections::HashSet;
+
+use matrix_sdk::{ruma::OwnedRoomId, Room, RoomState};
+use rinf::debug_print;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        subscriptions::Subscriptions,
+    },
+    messages::*,
+};
+
+impl From<RoomState> for RoomMembership {
+    fn from(value: RoomState) -> Self {
+        match value {
+            RoomState::Joined => Self::Joined,
+            RoomState::Invited => Self::Invited,
+            RoomState::Knocked => Self::Knocked,
+            RoomState::Left => Self::Left,
+            RoomState::Banned => Self::Banned,
+        }
+    }
+}
+
+/// The summary of a room, as shown in the room list.
+pub async fn summary(room: &Room) -> RoomSummary {
+    RoomSummary {
+        room_id: room.room_id().to_string(),
+        display_name: room
+            .display_name()
+            .await
+            .map(|name| name.to_string())
+            .unwrap_or_default(),
+        topic: room.topic().unwrap_or_default(),
+        avatar_url: room
+            .avatar_url()
+            .map(|url| url.to_string())
+            .unwrap_or_default(),
+        canonical_alias: room
+            .canonical_alias()
+            .map(|alias| alias.to_string())
+            .unwrap_or_default(),
+        is_direct: room.is_direct().await.unwrap_or_default(),
+        is_encrypted: room.encryption_state().is_encrypted(),
+        membership: RoomMembership::from(room.state()).into(),
+        // Computed by the client from the read receipts, which also works in
+        // encrypted rooms.
+        unread_messages: room.num_unread_messages(),
+        unread_notifications: room.num_unread_notifications(),
+        unread_mentions: room.num_unread_mentions(),
+        is_marked_unread: room.is_marked_unread(),
+        has_draft: room
+            .load_composer_draft()
+            .await
+            .is_ok_and(|draft| draft.is_some()),
+    }
+}
+
+/// Sends the summaries of the given rooms to Dart.
+///
+/// `reset` tells Dart to drop the rooms it doesn't get.
+pub async fn send_summaries(id: String, rooms: &[Room], reset: bool) {
+    let mut summaries = Vec::with_capacity(rooms.len());
+    for room in rooms {
+        summaries.push(summary(room).await);
+    }
+
+    RoomListUpdated {
+        id,
+        rooms: summaries,
+        reset,
+        error: Default::default(),
+    }
+    .send_signal_to_dart();
+}
+
+/// Streams the summaries of the rooms that got updates from the sync,
+/// starting with all the known rooms.
+async fn watch(id: String, client: MatrixClient) {
+    let mut updates = client.0.subscribe_to_all_room_updates();
+    send_summaries(id.clone(), &client.0.rooms(), true).await;
+
+    loop {
+        let room_ids: HashSet<OwnedRoomId> = match updates.recv().await {
+            Ok(updates) => updates
+                .joined
+                .into_keys()
+                .chain(updates.invited.into_keys())
+                .chain(updates.left.into_keys())
+                .chain(updates.knocked.into_keys())
+                .collect(),
+            Err(RecvError::Lagged(_)) => {
+                send_summaries(id.clone(), &client.0.rooms(), true).await;
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let rooms: Vec<Room> = room_ids
+            .iter()
+            .filter_map(|room_id| client.0.get_room(room_id))
+            .collect();
+        if !rooms.is_empty() {
+            send_summaries(id.clone(), &rooms, false).await;
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    let mut watched = Subscriptions::default();
+    let receiver = SubscribeRoomList::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SubscribeRoomList = dart_signal.message;
+        debug_print!("SubscribeRoomList: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let Some(client) = client else {
+            debug_print!(
+                "SubscribeRoomList: no client with associated {} was found",
+                &message.id
+            );
+            RoomListUpdated {
+                id: message.id,
+                error: "missing client".to_string(),
+                ..Default::default()
+            }
+            .send_signal_to_dart();
+            watched.stop(&message.id);
+            continue;
+        };
+
+        // Also when already streaming, so that it starts over with the full
+        // list.
+        let stream = watch(message.id.clone(), client.clone());
+        watched.start(message.id.clone(), &message.id, &client, stream);
+    }
+}
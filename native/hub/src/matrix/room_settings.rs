@@ -0,0 +1,306 @@
+use matrix_sdk::{
+    ruma::{
+        events::room::{
+            guest_access::{GuestAccess, RoomGuestAccessEventContent},
+            history_visibility::HistoryVisibility as SdkHistoryVisibility,
+            join_rules::{AllowRule, JoinRule as SdkJoinRule, Restricted},
+        },
+        IdParseError, OwnedRoomAliasId, RoomAliasId, RoomId,
+    },
+    Room,
+};
+use rinf::debug_print;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        media,
+        subscriptions::Subscriptions,
+    },
+    messages::*,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Media(#[from] media::Error),
+    #[error("A restricted room needs at least one room to allow the members of.")]
+    MissingAllowedRooms,
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+impl RoomJoinRule {
+    fn from_sdk(value: &SdkJoinRule) -> (Self, Vec<String>) {
+        match value {
+            SdkJoinRule::Public => (Self::Public, Vec::new()),
+            SdkJoinRule::Knock => (Self::Knock, Vec::new()),
+            SdkJoinRule::Restricted(restricted) | SdkJoinRule::KnockRestricted(restricted) => {
+                let allowed = restricted
+                    .allow
+                    .iter()
+                    .filter_map(|rule| match rule {
+                        AllowRule::RoomMembership(membership) => {
+                            Some(membership.room_id.to_string())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+                (Self::Restricted, allowed)
+            }
+            _ => (Self::Invite, Vec::new()),
+        }
+    }
+
+    /// `None` leaves the join rule unchanged.
+    fn into_sdk(self, allowed_room_ids: &[String]) -> Result<Option<SdkJoinRule>, Error> {
+        Ok(match self {
+            RoomJoinRule::Unspecified => None,
+            RoomJoinRule::Invite => Some(SdkJoinRule::Invite),
+            RoomJoinRule::Public => Some(SdkJoinRule::Public),
+            RoomJoinRule::Knock => Some(SdkJoinRule::Knock),
+            RoomJoinRule::Restricted => {
+                if allowed_room_ids.is_empty() {
+                    return Err(Error::MissingAllowedRooms);
+                }
+                let allow = allowed_room_ids
+                    .iter()
+                    .map(|room_id| Ok(AllowRule::room_membership(RoomId::parse(room_id)?)))
+                    .collect::<Result<Vec<AllowRule>, Error>>()?;
+                Some(SdkJoinRule::Restricted(Restricted::new(allow)))
+            }
+        })
+    }
+}
+
+impl From<SdkHistoryVisibility> for RoomHistoryVisibility {
+    fn from(value: SdkHistoryVisibility) -> Self {
+        match value {
+            SdkHistoryVisibility::Invited => Self::Invited,
+            SdkHistoryVisibility::Joined => Self::Joined,
+            SdkHistoryVisibility::WorldReadable => Self::WorldReadable,
+            _ => Self::Shared,
+        }
+    }
+}
+
+impl RoomHistoryVisibility {
+    /// `None` leaves the history visibility unchanged.
+    fn into_sdk(self) -> Option<SdkHistoryVisibility> {
+        match self {
+            RoomHistoryVisibility::Unspecified => None,
+            RoomHistoryVisibility::Invited => Some(SdkHistoryVisibility::Invited),
+            RoomHistoryVisibility::Joined => Some(SdkHistoryVisibility::Joined),
+            RoomHistoryVisibility::Shared => Some(SdkHistoryVisibility::Shared),
+            RoomHistoryVisibility::WorldReadable => Some(SdkHistoryVisibility::WorldReadable),
+        }
+    }
+}
+
+fn room(client: &MatrixClient, room_id: &str) -> Result<Room, Error> {
+    client
+        .0
+        .get_room(&RoomId::parse(room_id)?)
+        .ok_or(Error::RoomNotFound)
+}
+
+pub fn settings_state(room: &Room) -> RoomSettingsState {
+    let (join_rule, allowed_room_ids) = room
+        .join_rule()
+        .map(|join_rule| RoomJoinRule::from_sdk(&join_rule))
+        .unwrap_or((RoomJoinRule::Invite, Vec::new()));
+
+    RoomSettingsState {
+        id: Default::default(),
+        room_id: room.room_id().to_string(),
+        name: room.name().unwrap_or_default(),
+        topic: room.topic().unwrap_or_default(),
+        avatar_url: room
+            .avatar_url()
+            .map(|url| url.to_string())
+            .unwrap_or_default(),
+        canonical_alias: room
+            .canonical_alias()
+            .map(|alias| alias.to_string())
+            .unwrap_or_default(),
+        alt_aliases: room
+            .alt_aliases()
+            .into_iter()
+            .map(|alias| alias.to_string())
+            .collect(),
+        join_rule: join_rule.into(),
+        allowed_room_ids,
+        guest_access: room.guest_access() == GuestAccess::CanJoin,
+        history_visibility: RoomHistoryVisibility::from(room.history_visibility_or_default())
+            .into(),
+        is_encrypted: room.encryption_state().is_encrypted(),
+        error: Default::default(),
+    }
+}
+
+/// Applies the changes of an `UpdateRoomSettings`, each one being its own
+/// state event.
+pub async fn update_settings(
+    client: &MatrixClient,
+    room: &Room,
+    mut message: UpdateRoomSettings,
+) -> Result<(), Error> {
+    if let Some(name) = message.name.take() {
+        room.set_name(name).await?;
+    }
+    if let Some(topic) = &message.topic {
+        room.set_room_topic(topic).await?;
+    }
+    if message.remove_avatar {
+        room.remove_avatar().await?;
+    } else if !message.avatar.is_empty() {
+        let avatar = std::mem::take(&mut message.avatar);
+        let url = media::upload(client, &message.avatar_content_type, avatar).await?;
+        room.set_avatar_url(&url, None).await?;
+    }
+
+    if message.update_aliases {
+        let canonical_alias = match message.canonical_alias.as_str() {
+            "" => None,
+            alias => Some(RoomAliasId::parse(alias)?),
+        };
+        let alt_aliases = message
+            .alt_aliases
+            .iter()
+            .map(|alias| RoomAliasId::parse(alias.as_str()))
+            .collect::<Result<Vec<OwnedRoomAliasId>, _>>()?;
+        room.privacy_settings()
+            .update_canonical_alias(canonical_alias, alt_aliases)
+            .await?;
+    }
+
+    if let Some(join_rule) = message.join_rule().into_sdk(&message.allowed_room_ids)? {
+        room.privacy_settings().update_join_rule(join_rule).await?;
+    }
+    if let Some(guest_access) = message.guest_access {
+        let guest_access = if guest_access {
+            GuestAccess::CanJoin
+        } else {
+            GuestAccess::Forbidden
+        };
+        room.send_state_event(RoomGuestAccessEventContent::new(guest_access))
+            .await?;
+    }
+    if let Some(history_visibility) = message.history_visibility().into_sdk() {
+        room.privacy_settings()
+            .update_room_history_visibility(history_visibility)
+            .await?;
+    }
+    // Encryption can't be disabled once enabled.
+    if message.enable_encryption {
+        room.enable_encryption().await?;
+    }
+
+    Ok(())
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+/// Streams the settings of a room to Dart whenever its state changes, like
+/// after an `UpdateRoomSettings` comes back from the sync.
+async fn watch(id: String, room: Room) {
+    let mut info = room.subscribe_info();
+    while info.next().await.is_some() {
+        RoomSettingsState {
+            id: id.clone(),
+            ..settings_state(&room)
+        }
+        .send_signal_to_dart();
+    }
+}
+
+async fn communicate_get(clients: ArcMatrixClients) {
+    let mut watched = Subscriptions::default();
+    let receiver = GetRoomSettings::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetRoomSettings = dart_signal.message;
+        debug_print!("GetRoomSettings: received {message:?}");
+
+        let key = (message.id.clone(), message.room_id.clone());
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => room(&client, &message.room_id).map(|room| (client, room)),
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok((client, room)) => {
+                RoomSettingsState {
+                    id: message.id.clone(),
+                    ..settings_state(&room)
+                }
+                .send_signal_to_dart();
+                watched.start(key, &message.id, &client, watch(message.id.clone(), room));
+            }
+            Err(err) => {
+                watched.stop(&key);
+                debug_print!("GetRoomSettings: err {err:?}");
+                RoomSettingsState {
+                    id: message.id,
+                    room_id: message.room_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+/// The new settings are streamed once the sync brings them back, to the
+/// watchers of `GetRoomSettings` and to the room list.
+async fn communicate_update(clients: ArcMatrixClients) {
+    let receiver = UpdateRoomSettings::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: UpdateRoomSettings = dart_signal.message;
+        debug_print!(
+            "UpdateRoomSettings: received {} room: {}",
+            message.id,
+            message.room_id
+        );
+
+        let id = message.id.clone();
+        let room_id = message.room_id.clone();
+        let result = match client(&clients, &id).await {
+            Ok(client) => match room(&client, &room_id) {
+                Ok(room) => update_settings(&client, &room, message).await,
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            debug_print!("UpdateRoomSettings: err {err:?}");
+        }
+
+        RoomSettingsUpdated {
+            id,
+            room_id,
+            error: result.err().map(|err| err.to_string()).unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_get(clients.clone()),
+        communicate_update(clients),
+    );
+}
@@ -0,0 +1,326 @@
+use std::sync::Arc;
+
+use matrix_sdk::{
+    ruma::{
+        api::client::{
+            filter::{RoomEventFilter, UrlFilter},
+            search::search_events::v3::{
+                Categories, Criteria, EventContext, OrderBy, Request as SearchRequest,
+            },
+        },
+        events::{
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, AnyTimelineEvent, SyncMessageLikeEvent,
+        },
+        serde::Raw,
+        IdParseError, OwnedRoomId, RoomId, UInt, UserId,
+    },
+    Room,
+};
+use rinf::debug_print;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        search_index::{self, Filters, SearchIndex, SearchIndexes},
+    },
+    messages::*,
+};
+
+/// How many events around a hit are returned.
+const CONTEXT_SIZE: u32 = 1;
+const DEFAULT_LIMIT: usize = 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Http(#[from] matrix_sdk::HttpError),
+    #[error("The pagination token is invalid.")]
+    InvalidToken,
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+/// Where the next page starts, for the server and for the local search.
+///
+/// `None` means there's nothing left on that side.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PageToken {
+    server: Option<String>,
+    local: Option<usize>,
+}
+
+impl PageToken {
+    fn parse(token: &str) -> Result<Option<Self>, Error> {
+        if token.is_empty() {
+            return Ok(None);
+        }
+        serde_json::from_str(token)
+            .map(Some)
+            .map_err(|_| Error::InvalidToken)
+    }
+
+    fn is_done(&self) -> bool {
+        self.server.is_none() && self.local.is_none()
+    }
+}
+
+fn search_event(event: &AnySyncTimelineEvent, room_id: &RoomId) -> Option<SearchEvent> {
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(message),
+    )) = event
+    else {
+        return None;
+    };
+
+    Some(SearchEvent {
+        room_id: room_id.to_string(),
+        event_id: message.event_id.to_string(),
+        sender: message.sender.to_string(),
+        body: message.content.body().to_owned(),
+        timestamp: message.origin_server_ts.get().into(),
+    })
+}
+
+fn search_event_from_raw(event: &Raw<AnyTimelineEvent>) -> Option<SearchEvent> {
+    let room_id: OwnedRoomId = event.get_field("room_id").ok()??;
+    let event = event
+        .cast_ref::<AnySyncTimelineEvent>()
+        .deserialize()
+        .ok()?;
+    search_event(&event, &room_id)
+}
+
+/// Searches the unencrypted rooms on the server, which can't search the
+/// encrypted ones.
+///
+/// The server can't filter by date, the hits outside of the dates are dropped
+/// from its pages.
+async fn server_search(
+    client: &MatrixClient,
+    term: &str,
+    rooms: Option<Vec<OwnedRoomId>>,
+    filters: &Filters,
+    next_batch: Option<String>,
+) -> Result<(Vec<SearchHit>, Option<String>), Error> {
+    let mut filter = RoomEventFilter::default();
+    filter.rooms = rooms;
+    if let Some(sender) = &filters.sender {
+        filter.senders = Some(vec![UserId::parse(sender)?]);
+    }
+    if filters.has_media {
+        filter.url_filter = Some(UrlFilter::EventsWithUrl);
+    }
+
+    let mut criteria = Criteria::new(term.to_owned());
+    criteria.filter = filter;
+    criteria.order_by = Some(OrderBy::Rank);
+    criteria.event_context = EventContext {
+        before_limit: UInt::from(CONTEXT_SIZE),
+        after_limit: UInt::from(CONTEXT_SIZE),
+        include_profile: false,
+    };
+
+    let mut categories = Categories::new();
+    categories.room_events = Some(criteria);
+    let mut request = SearchRequest::new(categories);
+    request.next_batch = next_batch;
+
+    let response = client.0.send(request).await?;
+    let results = response.search_categories.room_events;
+
+    let hits = results
+        .results
+        .iter()
+        .filter_map(|result| {
+            let event = search_event_from_raw(result.result.as_ref()?)?;
+            let in_dates = filters.since.is_none_or(|since| event.timestamp >= since)
+                && filters.until.is_none_or(|until| event.timestamp <= until);
+            if !in_dates {
+                return None;
+            }
+            Some(SearchHit {
+                rank: result.rank.unwrap_or_default(),
+                context_before: result
+                    .context
+                    .events_before
+                    .iter()
+                    .filter_map(search_event_from_raw)
+                    .collect(),
+                context_after: result
+                    .context
+                    .events_after
+                    .iter()
+                    .filter_map(search_event_from_raw)
+                    .collect(),
+                encrypted: false,
+                event: Some(event),
+            })
+        })
+        .collect();
+
+    Ok((hits, results.next_batch))
+}
+
+/// A page of results. The ranks of the server and of the local index aren't
+/// comparable, so the two lists are kept apart.
+#[derive(Debug, Default)]
+pub struct Page {
+    pub hits: Vec<SearchHit>,
+    pub encrypted_hits: Vec<SearchHit>,
+    /// Why the encrypted rooms weren't searched, the server hits still are.
+    pub encrypted_error: Option<search_index::Error>,
+    pub next_batch: Option<String>,
+}
+
+/// Searches the messages of a room, or of all the joined rooms.
+///
+/// The local `index` is only needed for the encrypted rooms, the unencrypted
+/// ones are searched on the server even when it couldn't be opened.
+pub async fn search(
+    client: &MatrixClient,
+    index: Result<Arc<SearchIndex>, search_index::Error>,
+    message: &SearchMessages,
+) -> Result<Page, Error> {
+    let rooms = match message.room_id.as_str() {
+        "" => client.0.joined_rooms(),
+        room_id => vec![client
+            .0
+            .get_room(&RoomId::parse(room_id)?)
+            .ok_or(Error::RoomNotFound)?],
+    };
+    let (encrypted, unencrypted): (Vec<Room>, Vec<Room>) = rooms
+        .into_iter()
+        .partition(|room| room.encryption_state().is_encrypted());
+    let limit = match message.limit {
+        0 => DEFAULT_LIMIT,
+        limit => limit as usize,
+    };
+    let filters = Filters {
+        room_id: Some(message.room_id.clone()).filter(|room_id| !room_id.is_empty()),
+        sender: Some(message.sender.clone()).filter(|sender| !sender.is_empty()),
+        since: message.since,
+        until: message.until,
+        has_media: message.has_media,
+    };
+
+    // The first page starts on both sides.
+    let token = PageToken::parse(&message.next_batch)?.unwrap_or(PageToken {
+        server: (!unencrypted.is_empty()).then(String::new),
+        local: (!encrypted.is_empty()).then_some(0),
+    });
+
+    let mut page = Page::default();
+    let mut next = PageToken::default();
+    if let Some(server) = token.server {
+        let room_ids: Option<Vec<OwnedRoomId>> = (!message.room_id.is_empty()).then(|| {
+            unencrypted
+                .iter()
+                .map(|room| room.room_id().to_owned())
+                .collect()
+        });
+        let mut batch = Some(server).filter(|server| !server.is_empty());
+        // The hits out of the dates are dropped, the next pages of the server
+        // fill the page up to the limit.
+        loop {
+            let (hits, next_batch) = server_search(
+                client,
+                &message.search_term,
+                room_ids.clone(),
+                &filters,
+                batch,
+            )
+            .await?;
+            page.hits.extend(hits);
+            next.server = next_batch;
+            if page.hits.len() >= limit || next.server.is_none() {
+                break;
+            }
+            batch = next.server.clone();
+        }
+    }
+    if let Some(offset) = token.local {
+        let result = match index {
+            Ok(index) => {
+                index
+                    .search(
+                        message.search_term.clone(),
+                        filters,
+                        CONTEXT_SIZE as usize,
+                        offset,
+                        limit,
+                    )
+                    .await
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok((hits, has_more)) => {
+                page.encrypted_hits = hits;
+                next.local = has_more.then_some(offset + limit);
+            }
+            Err(err) => page.encrypted_error = Some(err),
+        }
+    }
+
+    if !next.is_done() {
+        page.next_batch = Some(serde_json::to_string(&next).map_err(|_| Error::InvalidToken)?);
+    }
+    Ok(page)
+}
+
+pub async fn communicate(clients: ArcMatrixClients, indexes: SearchIndexes) {
+    let receiver = SearchMessages::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SearchMessages = dart_signal.message;
+        debug_print!("SearchMessages: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => {
+                let index = search_index::index_for(&indexes, &message.id, &client).await;
+                search(&client, index, &message).await
+            }
+            None => Err(Error::MissingClient),
+        };
+
+        match result {
+            Ok(page) => {
+                if let Some(err) = &page.encrypted_error {
+                    debug_print!("SearchMessages: local search failed: {err:?}");
+                }
+                SearchResults {
+                    id: message.id,
+                    search_term: message.search_term,
+                    room_id: message.room_id,
+                    hits: page.hits,
+                    next_batch: page.next_batch.unwrap_or_default(),
+                    error: Default::default(),
+                    encrypted_hits: page.encrypted_hits,
+                    encrypted_error: page
+                        .encrypted_error
+                        .map(|err| err.to_string())
+                        .unwrap_or_default(),
+                }
+                .send_signal_to_dart()
+            }
+            Err(err) => {
+                debug_print!("SearchMessages: err {err:?}");
+                SearchResults {
+                    id: message.id,
+                    search_term: message.search_term,
+                    room_id: message.room_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
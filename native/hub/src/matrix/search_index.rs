@@ -0,0 +1,914 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    ops::Bound,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use matrix_sdk::{
+    ruma::{
+        events::room::{
+            message::{MessageType, OriginalSyncRoomMessageEvent, Relation},
+            redaction::OriginalSyncRoomRedactionEvent,
+        },
+        EventId, OwnedDeviceId, RoomId, UserId,
+    },
+    Room,
+};
+use matrix_sdk_ui::timeline::{MsgLikeKind, TimelineItem, TimelineItemContent};
+use rinf::debug_print;
+use serde::{Deserialize, Serialize};
+use tantivy::{
+    collector::{DocSetCollector, TopDocs},
+    query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery},
+    schema::{Field, IndexRecordOption, Schema, Value, FAST, INDEXED, STORED, STRING, TEXT},
+    Index, IndexReader, IndexWriter, Order, ReloadPolicy, Searcher, TantivyDocument, Term,
+};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+};
+
+use crate::{
+    matrix::{
+        client::MatrixClient,
+        store_key::{self, StoreKey},
+        subscriptions::{logged_out, HandlerGuard},
+    },
+    messages::*,
+};
+
+const FILE_NAME: &str = "search_index";
+/// How long changes wait to be written, so that a sync batch is saved once.
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+const WRITER_MEMORY: usize = 20_000_000;
+/// The log is rewritten once it has this many times more records than there
+/// are indexed events, plus `COMPACT_MIN`.
+const COMPACT_RATIO: usize = 2;
+const COMPACT_MIN: usize = 1_000;
+
+/// The open indexes, by client id, and the key they're saved with.
+#[derive(Clone)]
+pub struct SearchIndexes {
+    key: Option<StoreKey>,
+    open: Arc<Mutex<HashMap<String, OpenIndex>>>,
+}
+
+/// An index fed by the sync of its client, until the client is logged out.
+struct OpenIndex {
+    index: Arc<SearchIndex>,
+    /// Tells the client apart from a later one logged in under the same id.
+    device_id: Option<OwnedDeviceId>,
+    /// Saves the changes, and holds the event handlers feeding the index.
+    task: JoinHandle<()>,
+}
+
+impl SearchIndexes {
+    /// Without a key, the indexes are kept in memory only.
+    pub fn new(key: Option<StoreKey>) -> Self {
+        Self {
+            key,
+            open: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Tantivy(#[from] tantivy::TantivyError),
+    #[error(transparent)]
+    Key(#[from] store_key::Error),
+    #[error(transparent)]
+    Join(#[from] tokio::task::JoinError),
+    #[error("The client has no persistent store to keep the index in.")]
+    NoStore,
+}
+
+/// A message of an encrypted room, as kept in the index.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexedEvent {
+    pub room_id: String,
+    pub event_id: String,
+    pub sender: String,
+    pub body: String,
+    pub timestamp: u64,
+    pub has_media: bool,
+}
+
+impl IndexedEvent {
+    fn new(
+        room_id: &RoomId,
+        event_id: &EventId,
+        sender: &UserId,
+        timestamp: u64,
+        msgtype: &MessageType,
+    ) -> Self {
+        Self {
+            room_id: room_id.to_string(),
+            event_id: event_id.to_string(),
+            sender: sender.to_string(),
+            body: msgtype.body().to_owned(),
+            timestamp,
+            has_media: matches!(
+                msgtype,
+                MessageType::Image(_)
+                    | MessageType::Video(_)
+                    | MessageType::Audio(_)
+                    | MessageType::File(_)
+            ),
+        }
+    }
+
+    fn search_event(&self) -> SearchEvent {
+        SearchEvent {
+            room_id: self.room_id.clone(),
+            event_id: self.event_id.clone(),
+            sender: self.sender.clone(),
+            body: self.body.clone(),
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+/// What the hits are narrowed to, besides the search term.
+#[derive(Debug, Default)]
+pub struct Filters {
+    pub room_id: Option<String>,
+    pub sender: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub has_media: bool,
+}
+
+struct Fields {
+    room_id: Field,
+    event_id: Field,
+    sender: Field,
+    body: Field,
+    timestamp: Field,
+    has_media: Field,
+}
+
+impl Fields {
+    fn schema() -> (Schema, Self) {
+        let mut builder = Schema::builder();
+        let fields = Self {
+            room_id: builder.add_text_field("room_id", STRING | STORED),
+            event_id: builder.add_text_field("event_id", STRING | STORED),
+            sender: builder.add_text_field("sender", STRING | STORED),
+            body: builder.add_text_field("body", TEXT | STORED),
+            timestamp: builder.add_u64_field("timestamp", INDEXED | FAST | STORED),
+            has_media: builder.add_bool_field("has_media", INDEXED | STORED),
+        };
+        (builder.build(), fields)
+    }
+
+    fn document(&self, event: &IndexedEvent) -> TantivyDocument {
+        let mut document = TantivyDocument::default();
+        document.add_text(self.room_id, &event.room_id);
+        document.add_text(self.event_id, &event.event_id);
+        document.add_text(self.sender, &event.sender);
+        document.add_text(self.body, &event.body);
+        document.add_u64(self.timestamp, event.timestamp);
+        document.add_bool(self.has_media, event.has_media);
+        document
+    }
+
+    fn event(&self, document: &TantivyDocument) -> Option<IndexedEvent> {
+        let text = |field| Some(document.get_first(field)?.as_str()?.to_owned());
+        Some(IndexedEvent {
+            room_id: text(self.room_id)?,
+            event_id: text(self.event_id)?,
+            sender: text(self.sender)?,
+            body: text(self.body)?,
+            timestamp: document.get_first(self.timestamp)?.as_u64()?,
+            has_media: document.get_first(self.has_media)?.as_bool()?,
+        })
+    }
+}
+
+/// A change of the index, as written to its log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Change {
+    Add(IndexedEvent),
+    Remove(String),
+}
+
+impl Change {
+    fn event_id(&self) -> &str {
+        match self {
+            Change::Add(event) => &event.event_id,
+            Change::Remove(event_id) => event_id,
+        }
+    }
+}
+
+/// The file the changes of the index are appended to as they're committed,
+/// each encrypted on its own and preceded by its length.
+struct Log {
+    path: PathBuf,
+    key: StoreKey,
+    file: File,
+    records: usize,
+}
+
+impl Log {
+    /// Opens the log and reads its changes.
+    ///
+    /// A record cut short, by a crash in the middle of a write, is dropped.
+    /// A log that can't be decrypted is started over: the index fills up
+    /// again as the timelines are loaded.
+    fn open(path: PathBuf, key: StoreKey) -> Result<(Self, Vec<Change>), Error> {
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut changes = Vec::new();
+        let mut valid = 0;
+        while let Some(length) = data.get(valid..valid + 4) {
+            let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+            let Some(record) = data.get(valid + 4..valid + 4 + length) else {
+                break;
+            };
+            let change = key
+                .decrypt(record)
+                .ok()
+                .and_then(|plaintext| serde_json::from_slice(&plaintext).ok());
+            match change {
+                Some(change) => changes.push(change),
+                None => {
+                    debug_print!("SearchIndex: the index can't be decrypted, starting over");
+                    changes.clear();
+                    valid = 0;
+                    break;
+                }
+            }
+            valid += 4 + length;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)?;
+        file.set_len(valid as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        let records = changes.len();
+        Ok((
+            Self {
+                path,
+                key,
+                file,
+                records,
+            },
+            changes,
+        ))
+    }
+
+    fn encode(&self, changes: &[Change]) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        for change in changes {
+            let record = self.key.encrypt(&serde_json::to_vec(change)?)?;
+            data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            data.extend_from_slice(&record);
+        }
+        Ok(data)
+    }
+
+    fn append(&mut self, changes: &[Change]) -> Result<(), Error> {
+        let data = self.encode(changes)?;
+        self.file.write_all(&data)?;
+        self.file.sync_data()?;
+        self.records += changes.len();
+        Ok(())
+    }
+
+    /// Rewrites the log with only the indexed events.
+    ///
+    /// The new log is written next to the old one and renamed over it, so
+    /// that a crash leaves either of them whole.
+    fn compact(&mut self, events: Vec<IndexedEvent>) -> Result<(), Error> {
+        let changes: Vec<Change> = events.into_iter().map(Change::Add).collect();
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&self.encode(&changes)?)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = changes.len();
+        Ok(())
+    }
+}
+
+/// The full-text index of the messages of the encrypted rooms, which the
+/// server can't search.
+///
+/// The index itself lives in memory. Its changes are appended to a log
+/// encrypted with the store key, which rebuilds it when opened.
+///
+/// Committing, searching and writing the log block: they're meant to run
+/// with `spawn_blocking`, like `search` and the saving of the changes do.
+pub struct SearchIndex {
+    fields: Fields,
+    index: Index,
+    reader: IndexReader,
+    writer: std::sync::Mutex<IndexWriter>,
+    /// The changes that aren't committed yet, in order.
+    pending: std::sync::Mutex<Vec<Change>>,
+    log: Option<std::sync::Mutex<Log>>,
+    changed: Notify,
+}
+
+impl SearchIndex {
+    /// Builds an index of the events, kept in memory only.
+    pub fn new(events: Vec<IndexedEvent>) -> Result<Self, Error> {
+        Self::build(events, None)
+    }
+
+    fn build(
+        events: impl IntoIterator<Item = IndexedEvent>,
+        log: Option<Log>,
+    ) -> Result<Self, Error> {
+        let (schema, fields) = Fields::schema();
+        let index = Index::create_in_ram(schema);
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        let mut writer: IndexWriter = index.writer_with_num_threads(1, WRITER_MEMORY)?;
+        for event in events {
+            writer.add_document(fields.document(&event))?;
+        }
+        writer.commit()?;
+        reader.reload()?;
+
+        Ok(Self {
+            fields,
+            index,
+            reader,
+            writer: std::sync::Mutex::new(writer),
+            pending: Default::default(),
+            log: log.map(std::sync::Mutex::new),
+            changed: Notify::new(),
+        })
+    }
+
+    /// Opens the index of the client.
+    ///
+    /// Its log is kept next to the stores of the client, it isn't written
+    /// without a key to encrypt it.
+    async fn open(client: &MatrixClient, key: Option<StoreKey>) -> Result<Arc<Self>, Error> {
+        let path = client.1.as_ref().ok_or(Error::NoStore)?.join(FILE_NAME);
+        let index = tokio::task::spawn_blocking(move || {
+            let Some(key) = key else {
+                return Self::build(Vec::new(), None);
+            };
+            let (log, changes) = Log::open(path, key)?;
+            let mut events = HashMap::new();
+            for change in changes {
+                match change {
+                    Change::Add(event) => events.insert(event.event_id.clone(), event),
+                    Change::Remove(event_id) => events.remove(&event_id),
+                };
+            }
+            Self::build(events.into_values(), Some(log))
+        })
+        .await??;
+        Ok(Arc::new(index))
+    }
+
+    fn change(&self, change: Change) {
+        self.pending.lock().unwrap().push(change);
+        self.changed.notify_one();
+    }
+
+    /// Adds the event, or replaces it when it's edited.
+    pub fn add(&self, event: IndexedEvent) {
+        self.change(Change::Add(event));
+    }
+
+    /// Removes the event, once redacted.
+    pub fn remove(&self, event_id: &str) {
+        self.change(Change::Remove(event_id.to_owned()));
+    }
+
+    fn stored(&self, searcher: &Searcher, event_id: &str) -> Result<Option<IndexedEvent>, Error> {
+        let query = TermQuery::new(
+            Term::from_field_text(self.fields.event_id, event_id),
+            IndexRecordOption::Basic,
+        );
+        let Some((_, address)) = searcher.search(&query, &TopDocs::with_limit(1))?.pop() else {
+            return Ok(None);
+        };
+        Ok(self.fields.event(&searcher.doc(address)?))
+    }
+
+    /// The event as last indexed. Blocks.
+    fn get(&self, event_id: &str) -> Result<Option<IndexedEvent>, Error> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|change| change.event_id() == event_id)
+            .cloned();
+        match pending {
+            Some(Change::Add(event)) => Ok(Some(event)),
+            Some(Change::Remove(_)) => Ok(None),
+            None => self.stored(&self.reader.searcher(), event_id),
+        }
+    }
+
+    /// Indexes a decrypted message of the timeline, or removes it once
+    /// redacted.
+    pub fn add_timeline_item(&self, room_id: &RoomId, item: &TimelineItem) {
+        let Some(item) = item.as_event() else {
+            return;
+        };
+        let Some(event_id) = item.event_id() else {
+            return;
+        };
+        let TimelineItemContent::MsgLike(msg_like) = item.content() else {
+            return;
+        };
+        match &msg_like.kind {
+            MsgLikeKind::Message(message) => self.add(IndexedEvent::new(
+                room_id,
+                event_id,
+                item.sender(),
+                item.timestamp().get().into(),
+                message.msgtype(),
+            )),
+            MsgLikeKind::Redacted => self.remove(event_id.as_str()),
+            _ => {}
+        }
+    }
+
+    /// Commits the pending changes and appends them to the log. Blocks.
+    ///
+    /// The changes that don't change anything, like the messages a timeline
+    /// brings again, are dropped.
+    fn commit(&self) -> Result<(), Error> {
+        let mut writer = self.writer.lock().unwrap();
+        let changes = std::mem::take(&mut *self.pending.lock().unwrap());
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let searcher = self.reader.searcher();
+        let mut current: HashMap<String, Option<IndexedEvent>> = HashMap::new();
+        let mut applied = Vec::new();
+        for change in changes {
+            let event_id = change.event_id().to_owned();
+            let previous = match current.get(&event_id) {
+                Some(previous) => previous.clone(),
+                None => self.stored(&searcher, &event_id)?,
+            };
+            let next = match &change {
+                Change::Add(event) => Some(event.clone()),
+                Change::Remove(_) => None,
+            };
+            if previous == next {
+                continue;
+            }
+
+            writer.delete_term(Term::from_field_text(self.fields.event_id, &event_id));
+            if let Some(event) = &next {
+                writer.add_document(self.fields.document(event))?;
+            }
+            current.insert(event_id, next);
+            applied.push(change);
+        }
+        if applied.is_empty() {
+            return Ok(());
+        }
+
+        if let Some(log) = &self.log {
+            log.lock().unwrap().append(&applied)?;
+        }
+        writer.commit()?;
+        self.reader.reload()?;
+
+        if let Some(log) = &self.log {
+            let mut log = log.lock().unwrap();
+            let searcher = self.reader.searcher();
+            let indexed = searcher.num_docs() as usize;
+            if log.records > indexed * COMPACT_RATIO + COMPACT_MIN {
+                let addresses = searcher.search(&AllQuery, &DocSetCollector)?;
+                let mut events = Vec::with_capacity(addresses.len());
+                for address in addresses {
+                    events.extend(self.fields.event(&searcher.doc(address)?));
+                }
+                log.compact(events)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The events of the room in the range, the first `size` in `order`.
+    fn neighbours(
+        &self,
+        searcher: &Searcher,
+        room_id: &str,
+        range: (Bound<Term>, Bound<Term>),
+        order: Order,
+        size: usize,
+    ) -> Result<Vec<SearchEvent>, Error> {
+        let clauses: Vec<(Occur, Box<dyn Query>)> = vec![
+            (
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_text(self.fields.room_id, room_id),
+                    IndexRecordOption::Basic,
+                )),
+            ),
+            (Occur::Must, Box::new(RangeQuery::new(range.0, range.1))),
+        ];
+        let top_docs = searcher.search(
+            &BooleanQuery::new(clauses),
+            &TopDocs::with_limit(size).order_by_fast_field::<u64>("timestamp", order),
+        )?;
+
+        let mut events = Vec::with_capacity(top_docs.len());
+        for (_, address) in top_docs {
+            if let Some(event) = self.fields.event(&searcher.doc(address)?) {
+                events.push(event.search_event());
+            }
+        }
+        Ok(events)
+    }
+
+    /// The events of the room right before and right after the event.
+    fn context(
+        &self,
+        searcher: &Searcher,
+        event: &IndexedEvent,
+        size: usize,
+    ) -> Result<(Vec<SearchEvent>, Vec<SearchEvent>), Error> {
+        if size == 0 {
+            return Ok(Default::default());
+        }
+        let timestamp = Term::from_field_u64(self.fields.timestamp, event.timestamp);
+
+        let mut before = self.neighbours(
+            searcher,
+            &event.room_id,
+            (Bound::Unbounded, Bound::Excluded(timestamp.clone())),
+            Order::Desc,
+            size,
+        )?;
+        before.reverse();
+        let after = self.neighbours(
+            searcher,
+            &event.room_id,
+            (Bound::Excluded(timestamp), Bound::Unbounded),
+            Order::Asc,
+            size,
+        )?;
+        Ok((before, after))
+    }
+
+    /// Searches the indexed messages, best matches first, on a thread where
+    /// blocking is fine.
+    ///
+    /// Returns the hits, with `context_size` events around each, and whether
+    /// there are more after them.
+    pub async fn search(
+        self: &Arc<Self>,
+        term: String,
+        filters: Filters,
+        context_size: usize,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<SearchHit>, bool), Error> {
+        let index = self.clone();
+        tokio::task::spawn_blocking(move || {
+            index.search_blocking(&term, &filters, context_size, offset, limit)
+        })
+        .await?
+    }
+
+    fn search_blocking(
+        &self,
+        term: &str,
+        filters: &Filters,
+        context_size: usize,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<SearchHit>, bool), Error> {
+        self.commit()?;
+
+        let mut parser = QueryParser::for_index(&self.index, vec![self.fields.body]);
+        parser.set_conjunction_by_default();
+        // Whatever the user typed, the words that can be parsed are searched.
+        let (query, _) = parser.parse_query_lenient(term);
+
+        let term_query = |field, text: &str| -> Box<dyn Query> {
+            Box::new(TermQuery::new(
+                Term::from_field_text(field, text),
+                IndexRecordOption::Basic,
+            ))
+        };
+        let mut clauses = vec![(Occur::Must, query)];
+        if let Some(room_id) = &filters.room_id {
+            clauses.push((Occur::Must, term_query(self.fields.room_id, room_id)));
+        }
+        if let Some(sender) = &filters.sender {
+            clauses.push((Occur::Must, term_query(self.fields.sender, sender)));
+        }
+        if filters.since.is_some() || filters.until.is_some() {
+            let bound = |timestamp: Option<u64>| match timestamp {
+                Some(timestamp) => {
+                    Bound::Included(Term::from_field_u64(self.fields.timestamp, timestamp))
+                }
+                None => Bound::Unbounded,
+            };
+            clauses.push((
+                Occur::Must,
+                Box::new(RangeQuery::new(bound(filters.since), bound(filters.until))),
+            ));
+        }
+        if filters.has_media {
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(
+                    Term::from_field_bool(self.fields.has_media, true),
+                    IndexRecordOption::Basic,
+                )),
+            ));
+        }
+
+        let searcher = self.reader.searcher();
+        let top_docs = searcher.search(
+            &BooleanQuery::new(clauses),
+            &TopDocs::with_limit(limit + 1).and_offset(offset),
+        )?;
+        let has_more = top_docs.len() > limit;
+
+        let mut hits = Vec::with_capacity(limit);
+        for (score, address) in top_docs.into_iter().take(limit) {
+            let Some(event) = self.fields.event(&searcher.doc(address)?) else {
+                continue;
+            };
+            let (context_before, context_after) = self.context(&searcher, &event, context_size)?;
+            hits.push(SearchHit {
+                event: Some(event.search_event()),
+                rank: score.into(),
+                context_before,
+                context_after,
+                encrypted: true,
+            });
+        }
+        Ok((hits, has_more))
+    }
+}
+
+/// Commits and saves the changes a while after they're made.
+async fn save_changes(index: Arc<SearchIndex>) {
+    loop {
+        index.changed.notified().await;
+        tokio::time::sleep(SAVE_DELAY).await;
+        let committing = index.clone();
+        let result = match tokio::task::spawn_blocking(move || committing.commit()).await {
+            Ok(result) => result,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            debug_print!("SearchIndex: failed to save: {err:?}");
+        }
+    }
+}
+
+/// Indexes the messages of the encrypted rooms coming from the sync, which
+/// the SDK decrypted, and drops the redacted ones.
+fn register_handlers(client: &matrix_sdk::Client, index: &Arc<SearchIndex>) -> [HandlerGuard; 2] {
+    let messages = {
+        let index = index.clone();
+        client.add_event_handler(move |event: OriginalSyncRoomMessageEvent, room: Room| {
+            let index = index.clone();
+            async move {
+                if !room.encryption_state().is_encrypted() {
+                    return;
+                }
+                let indexed = match &event.content.relates_to {
+                    // Edits replace the content the original is indexed with.
+                    Some(Relation::Replacement(replacement)) => {
+                        let original = {
+                            let index = index.clone();
+                            let event_id = replacement.event_id.to_string();
+                            tokio::task::spawn_blocking(move || index.get(&event_id)).await
+                        };
+                        let Ok(Ok(Some(original))) = original else {
+                            return;
+                        };
+                        // Only the sender of a message can edit it.
+                        if original.sender != event.sender.as_str() {
+                            return;
+                        }
+                        let edited = IndexedEvent::new(
+                            room.room_id(),
+                            &replacement.event_id,
+                            &event.sender,
+                            original.timestamp,
+                            &replacement.new_content.msgtype,
+                        );
+                        IndexedEvent {
+                            body: edited.body,
+                            has_media: edited.has_media,
+                            ..original
+                        }
+                    }
+                    _ => IndexedEvent::new(
+                        room.room_id(),
+                        &event.event_id,
+                        &event.sender,
+                        event.origin_server_ts.get().into(),
+                        &event.content.msgtype,
+                    ),
+                };
+                index.add(indexed);
+            }
+        })
+    };
+
+    let index = index.clone();
+    let redactions = client.add_event_handler(move |event: OriginalSyncRoomRedactionEvent| {
+        let index = index.clone();
+        async move {
+            let redacts = event.content.redacts.as_ref().or(event.redacts.as_ref());
+            if let Some(redacts) = redacts {
+                index.remove(redacts.as_str());
+            }
+        }
+    });
+
+    [
+        HandlerGuard(client.clone(), messages),
+        HandlerGuard(client.clone(), redactions),
+    ]
+}
+
+/// The index of the client, opened on first use and fed from the sync.
+///
+/// It's dropped, along with the decrypted messages it holds, once the client
+/// is logged out or replaced by a new login under the same id.
+pub async fn index_for(
+    indexes: &SearchIndexes,
+    id: &str,
+    client: &MatrixClient,
+) -> Result<Arc<SearchIndex>, Error> {
+    let device_id = client.0.device_id().map(ToOwned::to_owned);
+    let mut open = indexes.open.lock().await;
+    if let Some(entry) = open.get(id) {
+        if entry.device_id == device_id && !entry.task.is_finished() {
+            return Ok(entry.index.clone());
+        }
+    }
+    if let Some(previous) = open.remove(id) {
+        previous.task.abort();
+    }
+
+    let index = SearchIndex::open(client, indexes.key.clone()).await?;
+    let handlers = register_handlers(&client.0, &index);
+    let mut session_changes = client.0.subscribe_to_session_changes();
+    let task = tokio::spawn({
+        let open = indexes.open.clone();
+        let index = index.clone();
+        let id = id.to_owned();
+        async move {
+            let _handlers = handlers;
+            tokio::select! {
+                _ = save_changes(index.clone()) => {}
+                _ = logged_out(&mut session_changes) => {}
+            }
+            let mut open = open.lock().await;
+            if open
+                .get(&id)
+                .is_some_and(|entry| Arc::ptr_eq(&entry.index, &index))
+            {
+                open.remove(&id);
+            }
+        }
+    });
+    open.insert(
+        id.to_owned(),
+        OpenIndex {
+            index: index.clone(),
+            device_id,
+            task,
+        },
+    );
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, Filters, IndexedEvent, Log, SearchIndex};
+    use crate::matrix::store_key::StoreKey;
+
+    fn event(event_id: &str, sender: &str, body: &str, timestamp: u64) -> IndexedEvent {
+        IndexedEvent {
+            room_id: "!room:example.org".to_owned(),
+            event_id: event_id.to_owned(),
+            sender: sender.to_owned(),
+            body: body.to_owned(),
+            timestamp,
+            has_media: body.ends_with(".png"),
+        }
+    }
+
+    fn event_ids(index: &SearchIndex, term: &str, filters: &Filters) -> Vec<String> {
+        let (hits, _) = index.search_blocking(term, filters, 1, 0, 10).unwrap();
+        hits.into_iter()
+            .map(|hit| hit.event.unwrap().event_id)
+            .collect()
+    }
+
+    #[test]
+    fn search_with_filters() {
+        let index = SearchIndex::new(vec![
+            event("$1", "@alice:example.org", "Lunch today?", 1),
+            event("$2", "@bob:example.org", "Lunch at noon", 2),
+            event("$3", "@alice:example.org", "lunch.png", 3),
+        ])
+        .unwrap();
+
+        let mut found = event_ids(&index, "lunch", &Filters::default());
+        found.sort();
+        assert_eq!(found, ["$1", "$2", "$3"]);
+
+        let filters = Filters {
+            sender: Some("@alice:example.org".to_owned()),
+            since: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(event_ids(&index, "lunch", &filters), ["$3"]);
+
+        let filters = Filters {
+            has_media: true,
+            ..Default::default()
+        };
+        assert_eq!(event_ids(&index, "lunch", &filters), ["$3"]);
+    }
+
+    #[test]
+    fn search_context_and_redaction() {
+        let index = SearchIndex::new(vec![
+            event("$1", "@alice:example.org", "Where?", 1),
+            event("$2", "@bob:example.org", "At the station", 2),
+            event("$3", "@alice:example.org", "Fine", 3),
+        ])
+        .unwrap();
+
+        let (hits, has_more) = index
+            .search_blocking("station", &Filters::default(), 1, 0, 10)
+            .unwrap();
+        assert!(!has_more);
+        assert_eq!(hits[0].context_before[0].event_id, "$1");
+        assert_eq!(hits[0].context_after[0].event_id, "$3");
+
+        index.remove("$2");
+        assert!(event_ids(&index, "station", &Filters::default()).is_empty());
+    }
+
+    #[test]
+    fn log_drops_torn_records_and_starts_over_when_unreadable() {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let key = StoreKey::from_bytes(&[7; 32]).unwrap();
+
+        let (mut log, changes) = Log::open(path.clone(), key.clone()).unwrap();
+        assert!(changes.is_empty());
+        log.append(&[Change::Add(event("$1", "@alice:example.org", "Hi", 1))])
+            .unwrap();
+        drop(log);
+
+        // A record cut short by a crash.
+        let mut data = std::fs::read(&path).unwrap();
+        let valid = data.len();
+        data.extend_from_slice(&[200, 0, 0, 0, 1, 2, 3]);
+        std::fs::write(&path, &data).unwrap();
+        let (log, changes) = Log::open(path.clone(), key).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), valid as u64);
+        drop(log);
+
+        let other = StoreKey::from_bytes(&[8; 32]).unwrap();
+        let (_, changes) = Log::open(path.clone(), other).unwrap();
+        assert!(changes.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use matrix_sdk::{
+    authentication::{
+        matrix::MatrixSession,
+        oauth::{ClientId, OAuthSession, UserSession},
+    },
+    ruma::{IdParseError, UserId},
+    AuthApi, ClientBuildError, SessionChange, SessionMeta, SessionTokens,
+};
+use rinf::debug_print;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        store_key::StoreKey,
+    },
+    messages::*,
+};
+
+/// Where the hub keeps the stores and sessions of logged in accounts.
+///
+/// Both are encrypted with the store key when the platform has a secure
+/// storage to keep it in.
+#[derive(Clone, Debug)]
+pub struct SessionStore {
+    data_directory: PathBuf,
+    key: Option<StoreKey>,
+}
+
+/// Everything needed to restore a logged in client, without going through
+/// the login flow again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PersistedSession {
+    pub homeserver_url: String,
+    pub store_path: PathBuf,
+    pub user_id: String,
+    pub device_id: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    /// The client id registered with the authorization server, `None` for
+    /// sessions logged in with a password.
+    pub oauth_client_id: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RestoreError {
+    #[error(transparent)]
+    Client(#[from] ClientBuildError),
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+}
+
+impl PersistedSession {
+    /// Returns `None` if the client isn't logged in or has no persistent
+    /// store.
+    pub fn from_client(client: &MatrixClient) -> Option<Self> {
+        let store_path = client.1.clone()?;
+        let meta = client.0.session_meta()?;
+        let tokens = client.0.session_tokens()?;
+        let oauth_client_id = match client.0.auth_api()? {
+            AuthApi::OAuth(oauth) => Some(oauth.client_id()?.as_str().to_owned()),
+            AuthApi::Matrix(_) => None,
+            _ => return None,
+        };
+
+        Some(Self {
+            homeserver_url: client.0.homeserver().into(),
+            store_path,
+            user_id: meta.user_id.to_string(),
+            device_id: meta.device_id.to_string(),
+            access_token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+            oauth_client_id,
+        })
+    }
+
+    /// Builds a client on top of the persisted stores and restores the
+    /// session into it, without making any request.
+    ///
+    /// The client doesn't refresh the tokens: the app, maybe running in
+    /// another process, does.
+    pub async fn restore(&self, sessions: &SessionStore) -> Result<MatrixClient, RestoreError> {
+        let client = MatrixClient::with_store_without_refresh(
+            &self.homeserver_url,
+            self.store_path.clone(),
+            sessions.passphrase().as_deref(),
+        )
+        .await?;
+
+        let meta = SessionMeta {
+            user_id: UserId::parse(&self.user_id)?,
+            device_id: self.device_id.as_str().into(),
+        };
+        let tokens = SessionTokens {
+            access_token: self.access_token.clone(),
+            refresh_token: self.refresh_token.clone(),
+        };
+
+        match &self.oauth_client_id {
+            Some(client_id) => {
+                client
+                    .0
+                    .restore_session(OAuthSession {
+                        client_id: ClientId::new(client_id.clone()),
+                        user: UserSession { meta, tokens },
+                    })
+                    .await?
+            }
+            None => {
+                client
+                    .0
+                    .restore_session(MatrixSession { meta, tokens })
+                    .await?
+            }
+        }
+
+        Ok(client)
+    }
+}
+
+impl SessionStore {
+    pub fn new(data_directory: impl Into<PathBuf>, key: Option<StoreKey>) -> Self {
+        Self {
+            data_directory: data_directory.into(),
+            key,
+        }
+    }
+
+    /// The passphrase of the sqlite stores, `None` if they aren't encrypted.
+    pub fn passphrase(&self) -> Option<String> {
+        self.key.as_ref().map(StoreKey::passphrase)
+    }
+
+    /// A fresh directory for the stores of a new login.
+    pub fn new_store_path(&self) -> PathBuf {
+        self.data_directory
+            .join("stores")
+            .join(uuid::Uuid::new_v4().to_string())
+    }
+
+    fn session_path(&self, user_id: &str) -> PathBuf {
+        // User ids contain characters that aren't allowed in file names on
+        // every platform.
+        let file_name: String = user_id.bytes().map(|b| format!("{b:02x}")).collect();
+        self.data_directory
+            .join("sessions")
+            .join(format!("{file_name}.json"))
+    }
+
+    pub async fn save(&self, session: &PersistedSession) -> io::Result<()> {
+        let path = self.session_path(&session.user_id);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut data = serde_json::to_vec(session)?;
+        if let Some(key) = &self.key {
+            data = key.encrypt(&data).map_err(invalid_data)?;
+        }
+        tokio::fs::write(path, data).await
+    }
+
+    pub async fn load(&self, user_id: &str) -> io::Result<Option<PersistedSession>> {
+        self.read(&self.session_path(user_id)).await
+    }
+
+    async fn read(&self, path: &Path) -> io::Result<Option<PersistedSession>> {
+        let mut data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if let Some(key) = &self.key {
+            data = key.decrypt(&data).map_err(invalid_data)?;
+        }
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    /// Forgets the session.
+    ///
+    /// Its stores, including the crypto store, are left to
+    /// `remove_orphan_stores`: the tasks of the app may still hold the
+    /// client, and so the stores open, until the next start.
+    pub async fn remove(&self, session: &PersistedSession) -> io::Result<()> {
+        match tokio::fs::remove_file(self.session_path(&session.user_id)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the stores of a client dropped before it logged in, like when
+    /// a login is abandoned or fails.
+    pub async fn discard(&self, client: MatrixClient) {
+        if client.0.session_meta().is_some() {
+            return;
+        }
+        let Some(store_path) = client.1.clone() else {
+            return;
+        };
+        // The stores must be closed before they're removed.
+        drop(client);
+        self.remove_store(&store_path).await;
+    }
+
+    /// Removes the stores of a login that failed.
+    pub async fn remove_store(&self, store_path: &Path) {
+        if let Err(err) = remove_dir(store_path).await {
+            debug_print!("SessionStore: failed to remove {store_path:?}: {err:?}");
+        }
+    }
+
+    /// Removes the stores no persisted session refers to, left behind by
+    /// logouts or when the app was stopped in the middle of a login.
+    ///
+    /// Must run before any login starts.
+    pub async fn remove_orphan_stores(&self) -> io::Result<()> {
+        let mut used = Vec::new();
+        match tokio::fs::read_dir(self.data_directory.join("sessions")).await {
+            Ok(mut entries) => {
+                while let Some(entry) = entries.next_entry().await? {
+                    match self.read(&entry.path()).await {
+                        Ok(Some(session)) => used.push(session.store_path),
+                        Ok(None) => {}
+                        Err(err) => {
+                            // The stores of a session that can't be read are
+                            // kept, in case it can be later.
+                            debug_print!("SessionStore: unreadable {:?}: {err:?}", entry.path());
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let mut entries = match tokio::fs::read_dir(self.data_directory.join("stores")).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if !used.contains(&entry.path()) {
+                remove_dir(&entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Persists the session of a freshly logged in client and starts
+    /// watching it for refreshed tokens and logouts.
+    pub async fn track(&self, clients: ArcMatrixClients, id: String, client: MatrixClient) {
+        let Some(session) = PersistedSession::from_client(&client) else {
+            debug_print!("SessionStore: client {id} has nothing to persist");
+            return;
+        };
+        if let Err(err) = self.save(&session).await {
+            debug_print!("SessionStore: failed to save session of {id}: {err:?}");
+        }
+
+        tokio::spawn(watch(self.clone(), clients, id, client));
+    }
+
+    /// Like `track`, for a client restored without the app, like to show a
+    /// notification. Such a client doesn't refresh the tokens, and Dart, which
+    /// doesn't know the client, isn't told about logouts.
+    pub fn track_in_background(&self, client: MatrixClient) {
+        tokio::spawn(watch_in_background(self.clone(), client));
+    }
+}
+
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+async fn remove_dir(path: &Path) -> io::Result<()> {
+    match tokio::fs::remove_dir_all(path).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+async fn watch_in_background(sessions: SessionStore, client: MatrixClient) {
+    let mut session_changes = client.0.subscribe_to_session_changes();
+    loop {
+        match session_changes.recv().await {
+            Ok(SessionChange::TokensRefreshed) => continue,
+            // The app finds out when it restores the session.
+            Ok(SessionChange::UnknownToken { soft_logout: true }) => break,
+            Ok(SessionChange::UnknownToken { soft_logout: false }) => {
+                if let Some(session) = PersistedSession::from_client(&client) {
+                    if let Err(err) = sessions.remove(&session).await {
+                        debug_print!("SessionStore: failed to remove session: {err:?}");
+                    }
+                }
+                break;
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn watch(
+    sessions: SessionStore,
+    clients: ArcMatrixClients,
+    id: String,
+    client: MatrixClient,
+) {
+    let mut session_changes = client.0.subscribe_to_session_changes();
+    loop {
+        match session_changes.recv().await {
+            Ok(SessionChange::TokensRefreshed) => {
+                let Some(session) = PersistedSession::from_client(&client) else {
+                    continue;
+                };
+                if let Err(err) = sessions.save(&session).await {
+                    debug_print!("SessionStore: failed to save session of {id}: {err:?}");
+                }
+            }
+            Ok(SessionChange::UnknownToken { soft_logout: true }) => {
+                // The stores are kept on purpose: logging in again with the
+                // same device id keeps the encryption keys, and so the
+                // encrypted history.
+                debug_print!("SessionStore: client {id} was soft logged out");
+                let meta = client.0.session_meta();
+                SoftLoggedOut {
+                    id,
+                    user_id: meta.map(|m| m.user_id.to_string()).unwrap_or_default(),
+                    device_id: meta.map(|m| m.device_id.to_string()).unwrap_or_default(),
+                }
+                .send_signal_to_dart();
+                break;
+            }
+            Ok(SessionChange::UnknownToken { soft_logout: false }) => {
+                debug_print!("SessionStore: client {id} was logged out");
+                let session = PersistedSession::from_client(&client);
+                clients.lock().await.remove(&id);
+                drop(client);
+                // Without its session, the stores are removed on the next
+                // start, once no task holds them open anymore.
+                if let Some(session) = session {
+                    if let Err(err) = sessions.remove(&session).await {
+                        debug_print!("SessionStore: failed to remove session of {id}: {err:?}");
+                    }
+                }
+                LoggedOut { id }.send_signal_to_dart();
+                break;
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("The data can't be encrypted.")]
+    Encryption,
+    #[error("The data can't be decrypted with the store key.")]
+    Decryption,
+}
+
+/// The key the sessions and the stores of the accounts are encrypted with.
+///
+/// Dart keeps it in the secure storage of the platform, it's never written
+/// next to what it protects.
+#[derive(Clone)]
+pub struct StoreKey(Key);
+
+impl fmt::Debug for StoreKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StoreKey(..)")
+    }
+}
+
+impl StoreKey {
+    /// Returns `None` if the bytes aren't a key, like when the platform has
+    /// no secure storage.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == KEY_SIZE).then(|| Self(*Key::from_slice(bytes)))
+    }
+
+    /// The passphrase of the sqlite stores.
+    pub fn passphrase(&self) -> String {
+        self.0.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// The nonce followed by the ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| Error::Encryption)?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_SIZE {
+            return Err(Error::Decryption);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::Decryption)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StoreKey;
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        assert!(StoreKey::from_bytes(&[7; 16]).is_none());
+        let key = StoreKey::from_bytes(&[7; 32]).unwrap();
+        let other = StoreKey::from_bytes(&[8; 32]).unwrap();
+
+        let data = key.encrypt(b"session").unwrap();
+        assert_eq!(key.decrypt(&data).unwrap(), b"session");
+        assert!(other.decrypt(&data).is_err());
+        assert!(key.decrypt(&data[..data.len() - 1]).is_err());
+    }
+}
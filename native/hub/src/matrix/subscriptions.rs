@@ -0,0 +1,97 @@
+use std::{collections::HashMap, future::Future, hash::Hash};
+
+use matrix_sdk::{event_handler::EventHandlerHandle, ruma::OwnedDeviceId, Client, SessionChange};
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+
+use crate::matrix::client::MatrixClient;
+
+/// Removes an event handler when dropped, so also when the task holding it is
+/// aborted.
+pub struct HandlerGuard(pub Client, pub EventHandlerHandle);
+
+impl Drop for HandlerGuard {
+    fn drop(&mut self) {
+        self.0.remove_event_handler(self.1.clone());
+    }
+}
+
+/// A stream to Dart, running for a client.
+struct Subscription {
+    id: String,
+    /// Tells the client apart from a later one logged in under the same id.
+    device_id: Option<OwnedDeviceId>,
+    task: JoinHandle<()>,
+}
+
+/// The streams Dart subscribed to, by key.
+///
+/// Subscribing again restarts the stream, so that it starts over with the
+/// current state. A stream stops once its client is logged out, or when a
+/// stream is started for a new login under the same id.
+pub struct Subscriptions<K>(HashMap<K, Subscription>);
+
+impl<K> Default for Subscriptions<K> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<K: Eq + Hash> Subscriptions<K> {
+    /// Starts the stream of the client with id `id`, in place of the one with
+    /// the same key.
+    pub fn start(
+        &mut self,
+        key: K,
+        id: &str,
+        client: &MatrixClient,
+        stream: impl Future<Output = ()> + Send + 'static,
+    ) {
+        let device_id = client.0.device_id().map(ToOwned::to_owned);
+        self.0.retain(|_, subscription| {
+            let replaced = subscription.id == id && subscription.device_id != device_id;
+            if replaced {
+                subscription.task.abort();
+            }
+            !replaced && !subscription.task.is_finished()
+        });
+        self.stop(&key);
+
+        let mut session_changes = client.0.subscribe_to_session_changes();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                _ = stream => {}
+                _ = logged_out(&mut session_changes) => {}
+            }
+        });
+        self.0.insert(
+            key,
+            Subscription {
+                id: id.to_owned(),
+                device_id,
+                task,
+            },
+        );
+    }
+
+    pub fn stop(&mut self, key: &K) {
+        if let Some(subscription) = self.0.remove(key) {
+            subscription.task.abort();
+        }
+    }
+}
+
+/// Returns once the client is logged out for good. A soft logout keeps the
+/// streams, the client logs in again with the same device.
+pub async fn logged_out(session_changes: &mut broadcast::Receiver<SessionChange>) {
+    loop {
+        match session_changes.recv().await {
+            Ok(SessionChange::UnknownToken { soft_logout: false }) | Err(RecvError::Closed) => {
+                return
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+        }
+    }
+}
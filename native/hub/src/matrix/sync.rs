@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use matrix_sdk::config::SyncSettings;
+use rinf::debug_print;
+use tokio::task::JoinHandle;
+
+use crate::{
+    matrix::{
+        client::ArcMatrixClients,
+        search_index::{self, SearchIndexes},
+    },
+    messages::*,
+};
+
+/// Starts and stops syncing logged in clients.
+///
+/// Rooms, their state and their timelines are only kept up to date while the
+/// client syncs, and a created room only comes down the sync that `CreateRoom`
+/// waits for. The search index is opened along, to index the messages of
+/// the encrypted rooms as they come.
+pub async fn communicate(clients: ArcMatrixClients, indexes: SearchIndexes) {
+    let mut syncs: HashMap<String, JoinHandle<()>> = HashMap::new();
+    let receiver = SetSyncing::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SetSyncing = dart_signal.message;
+        debug_print!("SetSyncing: received {message:?}");
+
+        syncs.retain(|_, handle| !handle.is_finished());
+
+        if !message.enabled {
+            if let Some(handle) = syncs.remove(&message.id) {
+                handle.abort();
+            }
+            continue;
+        }
+        if syncs.contains_key(&message.id) {
+            continue;
+        }
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let Some(client) = client else {
+            debug_print!(
+                "SetSyncing: no client with associated {} was found",
+                &message.id
+            );
+            SyncStopped {
+                id: message.id,
+                error: "missing client".to_string(),
+            }
+            .send_signal_to_dart();
+            continue;
+        };
+
+        let id = message.id.clone();
+        let indexes = indexes.clone();
+        let handle = tokio::spawn(async move {
+            if let Err(err) = client.0.event_cache().subscribe() {
+                debug_print!("SetSyncing: failed to subscribe the event cache: {err:?}");
+            }
+            if let Err(err) = search_index::index_for(&indexes, &id, &client).await {
+                debug_print!("SetSyncing: failed to open the search index: {err:?}");
+            }
+            // Only returns on errors, like an invalidated access token.
+            let error = match client.0.sync(SyncSettings::default()).await {
+                Ok(()) => Default::default(),
+                Err(err) => err.to_string(),
+            };
+            debug_print!("SetSyncing: sync of {id} stopped: {error}");
+            SyncStopped { id, error }.send_signal_to_dart();
+        });
+        syncs.insert(message.id, handle);
+    }
+}
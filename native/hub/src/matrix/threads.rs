@@ -0,0 +1,277 @@
+use futures_util::future::join_all;
+use matrix_sdk::{
+    room::{IncludeRelations, RelationsOptions},
+    ruma::{
+        api::client::threads::get_threads::v1::{IncludeThreads, Request as ThreadsRequest},
+        events::{
+            receipt::{Receipt, ReceiptThread, ReceiptType},
+            relation::RelationType,
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, AnyTimelineEvent, SyncMessageLikeEvent,
+        },
+        serde::Raw,
+        EventId, IdParseError, OwnedEventId, RoomId, UInt, UserId,
+    },
+    Room,
+};
+use rinf::debug_print;
+use serde::Deserialize;
+
+use crate::{
+    matrix::client::{ArcMatrixClients, MatrixClient},
+    messages::*,
+};
+
+/// How many replies are looked at to count the unread ones.
+const UNREAD_LIMIT: u32 = 50;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Http(#[from] matrix_sdk::HttpError),
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+/// The thread summary the server bundles with a thread root.
+#[derive(Debug, Deserialize)]
+struct BundledThread {
+    count: u64,
+    #[serde(default)]
+    current_user_participated: bool,
+    latest_event: Raw<AnyTimelineEvent>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BundledRelations {
+    #[serde(rename = "m.thread")]
+    thread: Option<BundledThread>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Unsigned {
+    #[serde(rename = "m.relations", default)]
+    relations: BundledRelations,
+}
+
+/// The sender, body and timestamp of an event, decrypted if needed.
+async fn event_preview(room: &Room, raw: &Raw<AnyTimelineEvent>) -> Option<ThreadEvent> {
+    let event_id: OwnedEventId = raw.get_field("event_id").ok()??;
+    let is_encrypted = raw.get_field::<String>("type").ok()?.as_deref() == Some("m.room.encrypted");
+
+    let event = if is_encrypted {
+        room.event(&event_id, None)
+            .await
+            .ok()?
+            .raw()
+            .deserialize()
+            .ok()?
+    } else {
+        raw.cast_ref::<AnySyncTimelineEvent>().deserialize().ok()?
+    };
+
+    let (body, unable_to_decrypt) = match &event {
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+            SyncMessageLikeEvent::Original(message),
+        )) => (message.content.body().to_owned(), false),
+        AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomEncrypted(_)) => {
+            (Default::default(), true)
+        }
+        _ => Default::default(),
+    };
+
+    Some(ThreadEvent {
+        event_id: event_id.to_string(),
+        sender: event.sender().to_string(),
+        body,
+        timestamp: event.origin_server_ts().get().into(),
+        unable_to_decrypt,
+    })
+}
+
+/// Counts the replies of others since the read receipt of the user in the
+/// thread, up to `UNREAD_LIMIT`.
+async fn unread_count(
+    room: &Room,
+    own_user_id: &UserId,
+    root: &EventId,
+    latest: &ThreadEvent,
+) -> Result<u32, Error> {
+    if latest.sender == own_user_id.as_str() {
+        return Ok(0);
+    }
+    // Private read receipts count too, the latest of both is the one.
+    let mut receipt: Option<(OwnedEventId, Receipt)> = None;
+    for receipt_type in [ReceiptType::Read, ReceiptType::ReadPrivate] {
+        let loaded = room
+            .load_user_receipt(
+                receipt_type,
+                ReceiptThread::Thread(root.to_owned()),
+                own_user_id,
+            )
+            .await?;
+        if let Some((event_id, loaded)) = loaded {
+            if receipt
+                .as_ref()
+                .is_none_or(|(_, latest)| loaded.ts > latest.ts)
+            {
+                receipt = Some((event_id, loaded));
+            }
+        }
+    }
+    let receipt = receipt.map(|(event_id, _)| event_id);
+    if receipt
+        .as_ref()
+        .is_some_and(|id| id.as_str() == latest.event_id)
+    {
+        return Ok(0);
+    }
+
+    // The newest replies come first.
+    let options = RelationsOptions {
+        include_relations: IncludeRelations::RelationsOfType(RelationType::Thread),
+        limit: Some(UInt::from(UNREAD_LIMIT)),
+        ..Default::default()
+    };
+    let relations = room.relations(root.to_owned(), options).await?;
+
+    let mut count = 0;
+    for event in &relations.chunk {
+        let event_id: Option<OwnedEventId> = event.raw().get_field("event_id").ok().flatten();
+        if event_id.is_some() && event_id == receipt {
+            break;
+        }
+        let sender: Option<String> = event.raw().get_field("sender").ok().flatten();
+        if sender.as_deref() != Some(own_user_id.as_str()) {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Summarizes a thread from the summary the server bundled with its root.
+async fn summarize(
+    room: &Room,
+    own_user_id: Option<&UserId>,
+    root: &Raw<AnyTimelineEvent>,
+) -> Result<Option<ThreadSummary>, Error> {
+    let Some(root_event) = event_preview(room, root).await else {
+        return Ok(None);
+    };
+    let thread = root
+        .get_field::<Unsigned>("unsigned")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+        .relations
+        .thread;
+
+    let mut summary = ThreadSummary {
+        reply_count: thread
+            .as_ref()
+            .map(|thread| thread.count)
+            .unwrap_or_default(),
+        participated: thread
+            .as_ref()
+            .is_some_and(|thread| thread.current_user_participated),
+        ..Default::default()
+    };
+    if let Some(thread) = &thread {
+        if let Some(latest) = event_preview(room, &thread.latest_event).await {
+            if let Some(own_user_id) = own_user_id {
+                let root_id = EventId::parse(&root_event.event_id)?;
+                summary.unread_count = unread_count(room, own_user_id, &root_id, &latest)
+                    .await
+                    .unwrap_or_else(|err| {
+                        debug_print!("ListThreads: failed to count unread: {err:?}");
+                        0
+                    });
+            }
+            summary.latest_reply = Some(latest);
+        }
+    }
+    summary.root = Some(root_event);
+
+    Ok(Some(summary))
+}
+
+/// A page of the threads of the room, the most recently active first.
+///
+/// The threads of the page are summarized concurrently, as decrypting their
+/// events and counting their unread replies may need requests.
+pub async fn list_threads(
+    client: &MatrixClient,
+    message: &ListThreads,
+) -> Result<(Vec<ThreadSummary>, Option<String>), Error> {
+    let room = client
+        .0
+        .get_room(&RoomId::parse(&message.room_id)?)
+        .ok_or(Error::RoomNotFound)?;
+
+    let mut request = ThreadsRequest::new(room.room_id().to_owned());
+    request.from = Some(message.from.clone()).filter(|from| !from.is_empty());
+    request.limit = Some(message.limit)
+        .filter(|limit| *limit > 0)
+        .map(UInt::from);
+    if message.only_participated {
+        request.include = IncludeThreads::Participated;
+    }
+    let response = client.0.send(request).await?;
+
+    let own_user_id = client.0.user_id();
+    let threads = join_all(
+        response
+            .chunk
+            .iter()
+            .map(|root| summarize(&room, own_user_id, root)),
+    )
+    .await
+    .into_iter()
+    .filter_map(Result::transpose)
+    .collect::<Result<_, _>>()?;
+
+    Ok((threads, response.next_batch))
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    let receiver = ListThreads::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ListThreads = dart_signal.message;
+        debug_print!("ListThreads: received {message:?}");
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => list_threads(&client, &message).await,
+            None => Err(Error::MissingClient),
+        };
+
+        match result {
+            Ok((threads, next_batch)) => ThreadList {
+                id: message.id,
+                room_id: message.room_id,
+                from: message.from,
+                threads,
+                next_batch: next_batch.unwrap_or_default(),
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("ListThreads: err {err:?}");
+                ThreadList {
+                    id: message.id,
+                    room_id: message.room_id,
+                    from: message.from,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
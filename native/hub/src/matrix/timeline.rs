@@ -0,0 +1,712 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{Arc, Weak},
+};
+
+use eyeball_im::{Vector, VectorDiff};
+use futures_util::StreamExt;
+use matrix_sdk::{
+    room::{IncludeRelations, RelationsOptions},
+    ruma::{
+        events::{
+            relation::{RelationType, Thread},
+            room::message::{
+                MessageType, Relation, RoomMessageEventContent,
+                RoomMessageEventContentWithoutRelation,
+            },
+            AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent,
+        },
+        EventId, IdParseError, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, UserId,
+    },
+    SessionChange,
+};
+use matrix_sdk_ui::timeline::{
+    EditedContent, Error as TimelineError, EventTimelineItem, MsgLikeKind, RoomExt, Timeline,
+    TimelineDetails, TimelineEventItemId, TimelineFocus, TimelineItem, TimelineItemContent,
+    TimelineItemKind, VirtualTimelineItem,
+};
+use rinf::debug_print;
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        recent_emoji,
+        search_index::{self, SearchIndex, SearchIndexes},
+        subscriptions::{logged_out, Subscriptions},
+    },
+    messages::*,
+};
+
+/// A timeline by client id, room and thread root.
+type TimelineKey = (String, OwnedRoomId, Option<OwnedEventId>);
+
+/// The timelines Dart subscribed to, by client id, room and thread root.
+pub type Timelines = Arc<Mutex<HashMap<TimelineKey, Arc<Timeline>>>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Timeline(#[from] TimelineError),
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("The kind of the action isn't specified.")]
+    UnspecifiedAction,
+    #[error("The event is malformed.")]
+    MalformedEvent,
+    #[error("Can't paginate {0} events at once.")]
+    TooManyEvents(u32),
+    #[error("missing client")]
+    MissingClient,
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+/// The timeline of a room, or of one of its threads, built on first use and
+/// then shared by the subscription and the commands.
+///
+/// An empty `thread_root_id` stands for the live timeline of the room. The
+/// timelines of a client are dropped once it's logged out, or replaced by a
+/// new login under the same id.
+pub async fn timeline_for(
+    clients: &ArcMatrixClients,
+    timelines: &Timelines,
+    id: &str,
+    room_id: &str,
+    thread_root_id: &str,
+) -> Result<Arc<Timeline>, Error> {
+    let room_id = RoomId::parse(room_id)?;
+    let thread_root_id = match thread_root_id {
+        "" => None,
+        thread_root_id => Some(EventId::parse(thread_root_id)?),
+    };
+    let key = (id.to_owned(), room_id.clone(), thread_root_id.clone());
+
+    let client = match client(clients, id).await {
+        Ok(client) => client,
+        Err(err) => {
+            timelines
+                .lock()
+                .await
+                .retain(|(entry_id, ..), _| entry_id != id);
+            return Err(err);
+        }
+    };
+    {
+        let mut cached = timelines.lock().await;
+        let device_id = client.0.device_id();
+        cached.retain(|(entry_id, ..), timeline| {
+            entry_id != id || timeline.room().client().device_id() == device_id
+        });
+        if let Some(timeline) = cached.get(&key) {
+            return Ok(timeline.clone());
+        }
+    }
+
+    let room = client.0.get_room(&room_id).ok_or(Error::RoomNotFound)?;
+    let timeline = match thread_root_id {
+        Some(root_event_id) => {
+            room.timeline_builder()
+                .with_focus(TimelineFocus::Thread { root_event_id })
+                .build()
+                .await?
+        }
+        None => room.timeline().await?,
+    };
+    let timeline = Arc::new(timeline);
+    match timelines.lock().await.entry(key.clone()) {
+        Entry::Occupied(entry) => Ok(entry.get().clone()),
+        Entry::Vacant(entry) => {
+            entry.insert(timeline.clone());
+            tokio::spawn(evict_on_logout(
+                timelines.clone(),
+                key,
+                Arc::downgrade(&timeline),
+                client.0.subscribe_to_session_changes(),
+            ));
+            Ok(timeline)
+        }
+    }
+}
+
+/// Drops the timeline from the cache once its client is logged out, or gone.
+async fn evict_on_logout(
+    timelines: Timelines,
+    key: TimelineKey,
+    timeline: Weak<Timeline>,
+    mut session_changes: broadcast::Receiver<SessionChange>,
+) {
+    logged_out(&mut session_changes).await;
+    let mut timelines = timelines.lock().await;
+    if timelines
+        .get(&key)
+        .is_some_and(|cached| Weak::ptr_eq(&Arc::downgrade(cached), &timeline))
+    {
+        timelines.remove(&key);
+    }
+}
+
+fn reactions(item: &EventTimelineItem, own_user_id: Option<&UserId>) -> Vec<ReactionGroup> {
+    let Some(reactions) = item.content().reactions() else {
+        return Vec::new();
+    };
+
+    reactions
+        .iter()
+        .map(|(key, senders)| ReactionGroup {
+            key: key.clone(),
+            count: senders.len() as u32,
+            includes_me: own_user_id.is_some_and(|own| senders.contains_key(own)),
+            senders: senders.keys().map(|sender| sender.to_string()).collect(),
+        })
+        .collect()
+}
+
+fn formatted_body(msgtype: &MessageType) -> Option<String> {
+    let formatted = match msgtype {
+        MessageType::Text(content) => content.formatted.as_ref(),
+        MessageType::Notice(content) => content.formatted.as_ref(),
+        MessageType::Emote(content) => content.formatted.as_ref(),
+        _ => None,
+    };
+    formatted.map(|formatted| formatted.body.clone())
+}
+
+pub fn event_entry(item: &EventTimelineItem, own_user_id: Option<&UserId>) -> TimelineEntry {
+    let mut entry = TimelineEntry {
+        kind: TimelineEntryKind::Event.into(),
+        event_id: item
+            .event_id()
+            .map(|event_id| event_id.to_string())
+            .unwrap_or_default(),
+        sender: item.sender().to_string(),
+        sender_name: match item.sender_profile() {
+            TimelineDetails::Ready(profile) => profile.display_name.clone().unwrap_or_default(),
+            _ => Default::default(),
+        },
+        timestamp: item.timestamp().get().into(),
+        is_own: item.is_own(),
+        is_editable: item.is_editable(),
+        reactions: reactions(item, own_user_id),
+        read_receipts: item
+            .read_receipts()
+            .iter()
+            .map(|(user_id, receipt)| ReadReceipt {
+                user_id: user_id.to_string(),
+                timestamp: receipt.ts.map(|ts| ts.get().into()).unwrap_or_default(),
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    if let TimelineItemContent::MsgLike(msg_like) = item.content() {
+        match &msg_like.kind {
+            MsgLikeKind::Message(message) => {
+                entry.body = message.body().to_owned();
+                entry.formatted_body = formatted_body(message.msgtype()).unwrap_or_default();
+                entry.is_edited = message.is_edited();
+            }
+            MsgLikeKind::Redacted => entry.is_redacted = true,
+            MsgLikeKind::UnableToDecrypt(_) => entry.unable_to_decrypt = true,
+            _ => {}
+        }
+    }
+
+    entry
+}
+
+pub fn entry(item: &TimelineItem, own_user_id: Option<&UserId>) -> TimelineEntry {
+    let unique_id = item.unique_id().0.clone();
+    match item.kind() {
+        TimelineItemKind::Event(event) => TimelineEntry {
+            unique_id,
+            ..event_entry(event, own_user_id)
+        },
+        TimelineItemKind::Virtual(VirtualTimelineItem::DateDivider(timestamp)) => TimelineEntry {
+            unique_id,
+            kind: TimelineEntryKind::DateDivider.into(),
+            timestamp: timestamp.get().into(),
+            ..Default::default()
+        },
+        TimelineItemKind::Virtual(VirtualTimelineItem::ReadMarker) => TimelineEntry {
+            unique_id,
+            kind: TimelineEntryKind::ReadMarker.into(),
+            ..Default::default()
+        },
+        TimelineItemKind::Virtual(VirtualTimelineItem::TimelineStart) => TimelineEntry {
+            unique_id,
+            kind: TimelineEntryKind::TimelineStart.into(),
+            ..Default::default()
+        },
+    }
+}
+
+fn diff(diff: VectorDiff<Arc<TimelineItem>>, own_user_id: Option<&UserId>) -> TimelineDiff {
+    let entries = |items: &Vector<Arc<TimelineItem>>| {
+        items
+            .iter()
+            .map(|item| entry(item, own_user_id))
+            .collect::<Vec<_>>()
+    };
+    let single = |item: &TimelineItem| vec![entry(item, own_user_id)];
+    let (op, index, items) = match diff {
+        VectorDiff::Append { values } => (TimelineDiffOp::Append, 0, entries(&values)),
+        VectorDiff::Clear => (TimelineDiffOp::Clear, 0, Vec::new()),
+        VectorDiff::PushFront { value } => (TimelineDiffOp::PushFront, 0, single(&value)),
+        VectorDiff::PushBack { value } => (TimelineDiffOp::PushBack, 0, single(&value)),
+        VectorDiff::PopFront => (TimelineDiffOp::PopFront, 0, Vec::new()),
+        VectorDiff::PopBack => (TimelineDiffOp::PopBack, 0, Vec::new()),
+        VectorDiff::Insert { index, value } => (TimelineDiffOp::Insert, index, single(&value)),
+        VectorDiff::Set { index, value } => (TimelineDiffOp::Set, index, single(&value)),
+        VectorDiff::Remove { index } => (TimelineDiffOp::Remove, index, Vec::new()),
+        VectorDiff::Truncate { length } => (TimelineDiffOp::Truncate, length, Vec::new()),
+        VectorDiff::Reset { values } => (TimelineDiffOp::Reset, 0, entries(&values)),
+    };
+
+    TimelineDiff {
+        op: op.into(),
+        index: index as u32,
+        items,
+    }
+}
+
+/// Feeds the messages the timeline decrypted to the search index, and drops
+/// the redacted ones.
+fn index_diff(index: &SearchIndex, room_id: &RoomId, diff: &VectorDiff<Arc<TimelineItem>>) {
+    match diff {
+        VectorDiff::Append { values } | VectorDiff::Reset { values } => {
+            for item in values {
+                index.add_timeline_item(room_id, item);
+            }
+        }
+        VectorDiff::PushFront { value }
+        | VectorDiff::PushBack { value }
+        | VectorDiff::Insert { value, .. }
+        | VectorDiff::Set { value, .. } => index.add_timeline_item(room_id, value),
+        _ => {}
+    }
+}
+
+/// Streams the items of a timeline to Dart, as diffs to apply to the list.
+///
+/// The `index` is only given for encrypted rooms, which the server can't
+/// search.
+async fn watch(
+    id: String,
+    room_id: String,
+    thread_root_id: String,
+    own_user_id: Option<OwnedUserId>,
+    timeline: Arc<Timeline>,
+    index: Option<Arc<SearchIndex>>,
+) {
+    let own_user_id = own_user_id.as_deref();
+    let (items, stream) = timeline.subscribe().await;
+    let index_diffs = |diffs: &[VectorDiff<Arc<TimelineItem>>]| {
+        if let Some(index) = &index {
+            for diff in diffs {
+                index_diff(index, timeline.room().room_id(), diff);
+            }
+        }
+    };
+
+    let reset = VectorDiff::Reset { values: items };
+    index_diffs(std::slice::from_ref(&reset));
+
+    TimelineUpdated {
+        id: id.clone(),
+        room_id: room_id.clone(),
+        thread_root_id: thread_root_id.clone(),
+        diffs: vec![diff(reset, own_user_id)],
+        error: Default::default(),
+    }
+    .send_signal_to_dart();
+
+    let mut stream = std::pin::pin!(stream);
+    while let Some(diffs) = stream.next().await {
+        index_diffs(&diffs);
+        TimelineUpdated {
+            id: id.clone(),
+            room_id: room_id.clone(),
+            thread_root_id: thread_root_id.clone(),
+            diffs: diffs
+                .into_iter()
+                .map(|update| diff(update, own_user_id))
+                .collect(),
+            error: Default::default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+/// The previous versions of an edited message, oldest first.
+///
+/// Only the edits of the sender of the message count, like for the shown
+/// content.
+pub async fn edit_history(
+    client: &MatrixClient,
+    room_id: &str,
+    event_id: &str,
+) -> Result<Vec<EditVersion>, Error> {
+    let room = client
+        .0
+        .get_room(&RoomId::parse(room_id)?)
+        .ok_or(Error::RoomNotFound)?;
+    let event_id = EventId::parse(event_id)?;
+    let original = room.event(&event_id, None).await?;
+    let sender = original
+        .raw()
+        .get_field::<OwnedUserId>("sender")
+        .ok()
+        .flatten()
+        .ok_or(Error::MalformedEvent)?;
+
+    let mut versions = Vec::new();
+    let mut from = None;
+    loop {
+        let options = RelationsOptions {
+            from,
+            include_relations: IncludeRelations::RelationsOfType(RelationType::Replacement),
+            ..Default::default()
+        };
+        let relations = room.relations(event_id.clone(), options).await?;
+        versions.extend(relations.chunk.iter().filter_map(|event| {
+            match event.raw().deserialize().ok()? {
+                AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+                    SyncMessageLikeEvent::Original(message),
+                )) if message.sender == sender => match message.content.relates_to {
+                    Some(Relation::Replacement(replacement)) => Some(EditVersion {
+                        event_id: message.event_id.to_string(),
+                        body: replacement.new_content.msgtype.body().to_owned(),
+                        formatted_body: formatted_body(&replacement.new_content.msgtype)
+                            .unwrap_or_default(),
+                        timestamp: message.origin_server_ts.get().into(),
+                    }),
+                    _ => None,
+                },
+                _ => None,
+            }
+        }));
+
+        match relations.next_batch_token {
+            Some(token) => from = Some(token),
+            None => break,
+        }
+    }
+    versions.sort_by_key(|version| version.timestamp);
+
+    Ok(versions)
+}
+
+/// Applies an edit, a redaction or a reaction toggle.
+pub async fn act(timeline: &Timeline, message: &TimelineAction) -> Result<(), Error> {
+    let event_id = EventId::parse(&message.event_id)?;
+    let item_id = TimelineEventItemId::EventId(event_id.clone());
+
+    match message.kind() {
+        TimelineActionKind::Unspecified => return Err(Error::UnspecifiedAction),
+        TimelineActionKind::Edit => {
+            let content = if message.formatted_body.is_empty() {
+                RoomMessageEventContentWithoutRelation::text_plain(&message.body)
+            } else {
+                RoomMessageEventContentWithoutRelation::text_html(
+                    &message.body,
+                    &message.formatted_body,
+                )
+            };
+            timeline
+                .edit(&item_id, EditedContent::RoomMessage(content))
+                .await?;
+        }
+        TimelineActionKind::Redact => {
+            let reason = Some(message.reason.as_str()).filter(|reason| !reason.is_empty());
+            timeline.redact(&item_id, reason).await?;
+        }
+        TimelineActionKind::ToggleReaction => {
+            // Only reacting counts as a use, not taking a reaction back.
+            let room = timeline.room();
+            let adding = match timeline.item_by_event_id(&event_id).await {
+                Some(item) => !reactions(&item, Some(room.own_user_id()))
+                    .iter()
+                    .any(|group| group.key == message.key && group.includes_me),
+                None => true,
+            };
+            timeline.toggle_reaction(&item_id, &message.key).await?;
+
+            if adding {
+                if let Err(err) = recent_emoji::record(&room.client(), &message.key).await {
+                    debug_print!("TimelineAction: failed to record the emoji: {err:?}");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a message, in the thread the timeline is focused on if any.
+pub async fn send_message(timeline: &Timeline, message: &SendMessage) -> Result<(), Error> {
+    let mut content = if message.formatted_body.is_empty() {
+        RoomMessageEventContent::text_plain(&message.body)
+    } else {
+        RoomMessageEventContent::text_html(&message.body, &message.formatted_body)
+    };
+
+    if !message.thread_root_id.is_empty() {
+        let root = EventId::parse(&message.thread_root_id)?;
+        let thread = if message.reply_to_event_id.is_empty() {
+            // Clients without threads show the message as a reply to the
+            // latest one of the thread.
+            let latest = timeline
+                .latest_event()
+                .await
+                .and_then(|item| item.event_id().map(ToOwned::to_owned))
+                .unwrap_or_else(|| root.clone());
+            Thread::plain(root, latest)
+        } else {
+            Thread::reply(root, EventId::parse(&message.reply_to_event_id)?)
+        };
+        content.relates_to = Some(Relation::Thread(thread));
+    }
+
+    timeline.send(content.into()).await?;
+    Ok(())
+}
+
+async fn communicate_subscribe(
+    clients: ArcMatrixClients,
+    timelines: Timelines,
+    indexes: SearchIndexes,
+) {
+    let mut watched = Subscriptions::default();
+    let receiver = SubscribeTimeline::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SubscribeTimeline = dart_signal.message;
+        debug_print!("SubscribeTimeline: received {message:?}");
+
+        let key = (
+            message.id.clone(),
+            message.room_id.clone(),
+            message.thread_root_id.clone(),
+        );
+        let result = match timeline_for(
+            &clients,
+            &timelines,
+            &message.id,
+            &message.room_id,
+            &message.thread_root_id,
+        )
+        .await
+        {
+            Ok(timeline) => client(&clients, &message.id)
+                .await
+                .map(|client| (timeline, client)),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok((timeline, client)) => {
+                let own_user_id = client.0.user_id().map(ToOwned::to_owned);
+                let index = if timeline.room().encryption_state().is_encrypted() {
+                    search_index::index_for(&indexes, &message.id, &client)
+                        .await
+                        .inspect_err(|err| {
+                            debug_print!("SubscribeTimeline: no search index: {err:?}")
+                        })
+                        .ok()
+                } else {
+                    None
+                };
+                // Also on a subscription to the same timeline, so that a new
+                // screen gets the items with a reset.
+                let stream = watch(
+                    message.id.clone(),
+                    message.room_id,
+                    message.thread_root_id,
+                    own_user_id,
+                    timeline,
+                    index,
+                );
+                watched.start(key, &message.id, &client, stream);
+            }
+            Err(err) => {
+                watched.stop(&key);
+                debug_print!("SubscribeTimeline: err {err:?}");
+                TimelineUpdated {
+                    id: message.id,
+                    room_id: message.room_id,
+                    thread_root_id: message.thread_root_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_paginate(clients: ArcMatrixClients, timelines: Timelines) {
+    let receiver = PaginateTimeline::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: PaginateTimeline = dart_signal.message;
+        debug_print!("PaginateTimeline: received {message:?}");
+
+        let result = match timeline_for(
+            &clients,
+            &timelines,
+            &message.id,
+            &message.room_id,
+            &message.thread_root_id,
+        )
+        .await
+        {
+            Ok(timeline) => match u16::try_from(message.count) {
+                Ok(count) => timeline
+                    .paginate_backwards(count)
+                    .await
+                    .map_err(Error::from),
+                Err(_) => Err(Error::TooManyEvents(message.count)),
+            },
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            debug_print!("PaginateTimeline: err {err:?}");
+        }
+
+        TimelinePaginated {
+            id: message.id,
+            room_id: message.room_id,
+            thread_root_id: message.thread_root_id,
+            reached_start: result.as_ref().is_ok_and(|reached_start| *reached_start),
+            error: result.err().map(|err| err.to_string()).unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+/// The changes are streamed with the timeline, once applied locally.
+async fn communicate_action(clients: ArcMatrixClients, timelines: Timelines) {
+    let receiver = TimelineAction::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: TimelineAction = dart_signal.message;
+        debug_print!("TimelineAction: received {message:?}");
+
+        let result = match timeline_for(
+            &clients,
+            &timelines,
+            &message.id,
+            &message.room_id,
+            &message.thread_root_id,
+        )
+        .await
+        {
+            Ok(timeline) => act(&timeline, &message).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            debug_print!("TimelineAction: err {err:?}");
+        }
+
+        TimelineActionDone {
+            id: message.id,
+            room_id: message.room_id,
+            thread_root_id: message.thread_root_id,
+            event_id: message.event_id,
+            kind: message.kind,
+            error: result.err().map(|err| err.to_string()).unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+/// The message is streamed with the timeline, as a local echo first.
+async fn communicate_send(clients: ArcMatrixClients, timelines: Timelines) {
+    let receiver = SendMessage::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SendMessage = dart_signal.message;
+        debug_print!(
+            "SendMessage: received {} room: {} thread: {}",
+            message.id,
+            message.room_id,
+            message.thread_root_id
+        );
+
+        let result = match timeline_for(
+            &clients,
+            &timelines,
+            &message.id,
+            &message.room_id,
+            &message.thread_root_id,
+        )
+        .await
+        {
+            Ok(timeline) => send_message(&timeline, &message).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = &result {
+            debug_print!("SendMessage: err {err:?}");
+        }
+
+        MessageSent {
+            id: message.id,
+            room_id: message.room_id,
+            thread_root_id: message.thread_root_id,
+            error: result.err().map(|err| err.to_string()).unwrap_or_default(),
+        }
+        .send_signal_to_dart();
+    }
+}
+
+async fn communicate_edit_history(clients: ArcMatrixClients) {
+    let receiver = GetEditHistory::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetEditHistory = dart_signal.message;
+        debug_print!("GetEditHistory: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => edit_history(&client, &message.room_id, &message.event_id).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(versions) => EditHistory {
+                id: message.id,
+                room_id: message.room_id,
+                event_id: message.event_id,
+                versions,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("GetEditHistory: err {err:?}");
+                EditHistory {
+                    id: message.id,
+                    room_id: message.room_id,
+                    event_id: message.event_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients, timelines: Timelines, indexes: SearchIndexes) {
+    tokio::join!(
+        communicate_subscribe(clients.clone(), timelines.clone(), indexes),
+        communicate_paginate(clients.clone(), timelines.clone()),
+        communicate_action(clients.clone(), timelines.clone()),
+        communicate_send(clients.clone(), timelines),
+        communicate_edit_history(clients),
+    );
+}
@@ -0,0 +1,202 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use matrix_sdk::{
+    ruma::{IdParseError, OwnedRoomId, OwnedUserId, RoomId},
+    Room,
+};
+use rinf::debug_print;
+use tokio::{sync::broadcast::error::RecvError, task::JoinHandle, time::Instant};
+
+use crate::{
+    matrix::{
+        client::{ArcMatrixClients, MatrixClient},
+        subscriptions::Subscriptions,
+    },
+    messages::*,
+};
+
+/// The least time between two typing notices, however fast the user types.
+const THROTTLE: Duration = Duration::from_secs(3);
+/// How long the user is still shown as typing after the last activity.
+const AUTO_STOP: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error("The room isn't known by the client.")]
+    RoomNotFound,
+    #[error("missing client")]
+    MissingClient,
+}
+
+fn room(client: &MatrixClient, room_id: &str) -> Result<Room, Error> {
+    client
+        .0
+        .get_room(&RoomId::parse(room_id)?)
+        .ok_or(Error::RoomNotFound)
+}
+
+/// Sends a typing notice in the background, failures only being logged.
+fn notify(room: Room, typing: bool) {
+    tokio::spawn(async move {
+        if let Err(err) = room.typing_notice(typing).await {
+            debug_print!("ComposerActivity: failed to send the typing notice: {err:?}");
+        }
+    });
+}
+
+/// The typing notices being sent in a room.
+struct Typing {
+    last_sent: Instant,
+    auto_stop: JoinHandle<()>,
+}
+
+async fn users(room: &Room, user_ids: Vec<OwnedUserId>) -> Vec<TypingUser> {
+    let mut users = Vec::with_capacity(user_ids.len());
+    for user_id in user_ids {
+        let display_name = room
+            .get_member_no_sync(&user_id)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|member| member.display_name().map(ToOwned::to_owned))
+            .unwrap_or_default();
+        users.push(TypingUser {
+            user_id: user_id.to_string(),
+            display_name,
+        });
+    }
+    users
+}
+
+/// The users typing in a room, as last streamed to Dart.
+type LastTyping = Arc<std::sync::Mutex<Vec<TypingUser>>>;
+
+/// Streams the users typing in a room, other than the user, starting with
+/// the ones last known.
+async fn watch(id: String, room: Room, last: LastTyping) {
+    let (_guard, mut receiver) = room.subscribe_to_typing_notifications();
+    let send = |users: Vec<TypingUser>| {
+        TypingUsers {
+            id: id.clone(),
+            room_id: room.room_id().to_string(),
+            users,
+            error: Default::default(),
+        }
+        .send_signal_to_dart()
+    };
+
+    send(last.lock().unwrap().clone());
+    loop {
+        match receiver.recv().await {
+            Ok(user_ids) => {
+                let users = users(&room, user_ids).await;
+                *last.lock().unwrap() = users.clone();
+                send(users);
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+async fn communicate_activity(clients: ArcMatrixClients) {
+    let mut typing: HashMap<(String, OwnedRoomId), Typing> = HashMap::new();
+    let receiver = ComposerActivity::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: ComposerActivity = dart_signal.message;
+
+        let client = clients.lock().await.get(&message.id).cloned();
+        let room = match client {
+            Some(client) => room(&client, &message.room_id),
+            None => Err(Error::MissingClient),
+        };
+        let room = match room {
+            Ok(room) => room,
+            Err(err) => {
+                debug_print!("ComposerActivity: err {err:?}");
+                continue;
+            }
+        };
+
+        let key = (message.id, room.room_id().to_owned());
+        let previous = typing.remove(&key);
+        if let Some(previous) = &previous {
+            previous.auto_stop.abort();
+        }
+
+        if !message.typing {
+            if previous.is_some() {
+                notify(room, false);
+            }
+            continue;
+        }
+
+        let last_sent = match previous {
+            Some(previous) if previous.last_sent.elapsed() < THROTTLE => previous.last_sent,
+            _ => {
+                notify(room.clone(), true);
+                Instant::now()
+            }
+        };
+        let auto_stop = tokio::spawn(async move {
+            tokio::time::sleep(AUTO_STOP).await;
+            notify(room, false);
+        });
+        typing.insert(
+            key,
+            Typing {
+                last_sent,
+                auto_stop,
+            },
+        );
+        // Forget the rooms the auto stop went off for.
+        typing.retain(|_, typing| !typing.auto_stop.is_finished());
+    }
+}
+
+async fn communicate_subscribe(clients: ArcMatrixClients) {
+    let mut watched = Subscriptions::default();
+    let mut last_typing: HashMap<(String, String), LastTyping> = HashMap::new();
+    let receiver = SubscribeTyping::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SubscribeTyping = dart_signal.message;
+        debug_print!("SubscribeTyping: received {message:?}");
+
+        let key = (message.id.clone(), message.room_id.clone());
+        let client = clients.lock().await.get(&message.id).cloned();
+        let result = match client {
+            Some(client) => room(&client, &message.room_id).map(|room| (client, room)),
+            None => Err(Error::MissingClient),
+        };
+        match result {
+            // Also on a subscription to the same room, so that it starts with
+            // the users typing.
+            Ok((client, room)) => {
+                let last = last_typing.entry(key.clone()).or_default().clone();
+                let stream = watch(message.id.clone(), room, last);
+                watched.start(key, &message.id, &client, stream);
+            }
+            Err(err) => {
+                watched.stop(&key);
+                last_typing.remove(&key);
+                debug_print!("SubscribeTyping: err {err:?}");
+                TypingUsers {
+                    id: message.id,
+                    room_id: message.room_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_activity(clients.clone()),
+        communicate_subscribe(clients),
+    );
+}
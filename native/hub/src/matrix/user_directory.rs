@@ -0,0 +1,351 @@
+use matrix_sdk::{
+    ruma::{IdParseError, MilliSecondsSinceUnixEpoch, OwnedUserId, UserId},
+    HttpError, StoreError,
+};
+use rinf::debug_print;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    matrix::client::{ArcMatrixClients, MatrixClient},
+    messages::*,
+};
+
+/// The prefix of the cached profiles in the state store, followed by the
+/// user id.
+const PROFILE_KEY_PREFIX: &str = "hub.profile.";
+/// The key of the last known recent contacts in the state store.
+const RECENT_CONTACTS_KEY: &[u8] = b"hub.recent_contacts";
+/// How long a cached profile is used before being fetched again.
+const PROFILE_TTL_MS: u64 = 60 * 60 * 1000;
+const DEFAULT_LIMIT: u32 = 10;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Id(#[from] IdParseError),
+    #[error(transparent)]
+    Http(#[from] HttpError),
+    #[error(transparent)]
+    Sdk(#[from] matrix_sdk::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("missing client")]
+    MissingClient,
+}
+
+/// A profile as cached in the state store.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedProfile {
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    /// Milliseconds since the epoch.
+    fetched_at: u64,
+}
+
+impl CachedProfile {
+    fn is_fresh(&self, now: u64) -> bool {
+        now.saturating_sub(self.fetched_at) < PROFILE_TTL_MS
+    }
+
+    fn profile(&self, user_id: &UserId) -> UserProfile {
+        UserProfile {
+            user_id: user_id.to_string(),
+            display_name: self.display_name.clone().unwrap_or_default(),
+            avatar_url: self.avatar_url.clone().unwrap_or_default(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    MilliSecondsSinceUnixEpoch::now().get().into()
+}
+
+fn profile_key(user_id: &UserId) -> Vec<u8> {
+    format!("{PROFILE_KEY_PREFIX}{user_id}").into_bytes()
+}
+
+async fn store_profile(
+    client: &MatrixClient,
+    user_id: &UserId,
+    profile: &CachedProfile,
+) -> Result<(), Error> {
+    client
+        .0
+        .state_store()
+        .set_custom_value(&profile_key(user_id), serde_json::to_vec(profile)?)
+        .await?;
+    Ok(())
+}
+
+/// Caches a profile, the profile is still usable if the cache can't be
+/// written.
+async fn cache_profile(client: &MatrixClient, user_id: &UserId, profile: &CachedProfile) {
+    if let Err(err) = store_profile(client, user_id, profile).await {
+        debug_print!("failed to cache the profile of {user_id}: {err:?}");
+    }
+}
+
+/// The profile of a user, from the cache unless it's expired or `refresh`
+/// is set.
+///
+/// An expired or unreadable cached profile is dropped, as profiles of users
+/// that aren't looked up again would otherwise stay in the store.
+pub async fn profile(
+    client: &MatrixClient,
+    user_id: &UserId,
+    refresh: bool,
+) -> Result<UserProfile, Error> {
+    if !refresh {
+        let store = client.0.state_store();
+        let key = profile_key(user_id);
+        if let Some(json) = store.get_custom_value(&key).await? {
+            // A cache that can't be read is fetched again.
+            match serde_json::from_slice::<CachedProfile>(&json) {
+                Ok(cached) if cached.is_fresh(now()) => return Ok(cached.profile(user_id)),
+                _ => {
+                    store.remove_custom_value(&key).await?;
+                }
+            }
+        }
+    }
+
+    let response = client.0.account().fetch_user_profile_of(user_id).await?;
+    let cached = CachedProfile {
+        display_name: response.displayname,
+        avatar_url: response.avatar_url.map(|url| url.to_string()),
+        fetched_at: now(),
+    };
+    cache_profile(client, user_id, &cached).await;
+    Ok(cached.profile(user_id))
+}
+
+/// Searches the user directory of the server.
+///
+/// The profiles of the results are cached along, the results are returned
+/// even if the cache can't be written.
+pub async fn search_users(
+    client: &MatrixClient,
+    term: &str,
+    limit: u32,
+) -> Result<(Vec<UserProfile>, bool), Error> {
+    let response = client.0.search_users(term, limit.into()).await?;
+
+    let mut users = Vec::with_capacity(response.results.len());
+    for user in response.results {
+        let cached = CachedProfile {
+            display_name: user.display_name,
+            avatar_url: user.avatar_url.map(|url| url.to_string()),
+            fetched_at: now(),
+        };
+        cache_profile(client, &user.user_id, &cached).await;
+        users.push(cached.profile(&user.user_id));
+    }
+    Ok((users, response.limited))
+}
+
+/// The users of the direct rooms, the most recently active first.
+///
+/// Before the first sync, the contacts found the last time are used.
+pub async fn recent_contacts(client: &MatrixClient, limit: u32) -> Result<Vec<UserProfile>, Error> {
+    let mut direct_rooms = Vec::new();
+    for room in client.0.joined_rooms() {
+        if !room.is_direct().await.unwrap_or_default() {
+            continue;
+        }
+        // The last event the client has of the room tells its activity.
+        let last_activity = match room.event_cache().await {
+            Ok((cache, _drop_handles)) => cache.events().await.last().and_then(|event| {
+                event
+                    .raw()
+                    .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                    .ok()
+                    .flatten()
+            }),
+            Err(_) => None,
+        };
+        direct_rooms.push((last_activity, room));
+    }
+    direct_rooms.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    let mut user_ids: Vec<OwnedUserId> = Vec::new();
+    for (_, room) in &direct_rooms {
+        for target in room.direct_targets() {
+            match target.as_user_id() {
+                Some(user_id) if !user_ids.iter().any(|known| known == user_id) => {
+                    user_ids.push(user_id.to_owned())
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let store = client.0.state_store();
+    if user_ids.is_empty() {
+        if let Some(json) = store.get_custom_value(RECENT_CONTACTS_KEY).await? {
+            user_ids = serde_json::from_slice(&json)?;
+        }
+    } else {
+        store
+            .set_custom_value(RECENT_CONTACTS_KEY, serde_json::to_vec(&user_ids)?)
+            .await?;
+    }
+
+    let mut contacts = Vec::new();
+    for user_id in user_ids.iter().take(limit as usize) {
+        match profile(client, user_id, false).await {
+            Ok(profile) => contacts.push(profile),
+            // A profile the server refuses to share still leaves the contact.
+            Err(err) => {
+                debug_print!("GetRecentContacts: no profile for {user_id}: {err:?}");
+                contacts.push(UserProfile {
+                    user_id: user_id.to_string(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    Ok(contacts)
+}
+
+async fn client(clients: &ArcMatrixClients, id: &str) -> Result<MatrixClient, Error> {
+    clients
+        .lock()
+        .await
+        .get(id)
+        .cloned()
+        .ok_or(Error::MissingClient)
+}
+
+fn limit(limit: u32) -> u32 {
+    match limit {
+        0 => DEFAULT_LIMIT,
+        limit => limit,
+    }
+}
+
+async fn communicate_search(clients: ArcMatrixClients) {
+    let receiver = SearchUserDirectory::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: SearchUserDirectory = dart_signal.message;
+        debug_print!("SearchUserDirectory: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => search_users(&client, &message.search_term, limit(message.limit)).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok((users, limited)) => UserDirectoryResults {
+                id: message.id,
+                search_term: message.search_term,
+                users,
+                limited,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("SearchUserDirectory: err {err:?}");
+                UserDirectoryResults {
+                    id: message.id,
+                    search_term: message.search_term,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_profile(clients: ArcMatrixClients) {
+    let receiver = GetUserProfile::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetUserProfile = dart_signal.message;
+        debug_print!("GetUserProfile: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => match UserId::parse(&message.user_id) {
+                Ok(user_id) => profile(&client, &user_id, message.refresh).await,
+                Err(err) => Err(err.into()),
+            },
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(profile) => UserProfileFetched {
+                id: message.id,
+                user_id: message.user_id,
+                profile: Some(profile),
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("GetUserProfile: err {err:?}");
+                UserProfileFetched {
+                    id: message.id,
+                    user_id: message.user_id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+async fn communicate_recent(clients: ArcMatrixClients) {
+    let receiver = GetRecentContacts::get_dart_signal_receiver();
+    while let Some(dart_signal) = receiver.recv().await {
+        let message: GetRecentContacts = dart_signal.message;
+        debug_print!("GetRecentContacts: received {message:?}");
+
+        let result = match client(&clients, &message.id).await {
+            Ok(client) => recent_contacts(&client, limit(message.limit)).await,
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(contacts) => RecentContacts {
+                id: message.id,
+                contacts,
+                error: Default::default(),
+            }
+            .send_signal_to_dart(),
+            Err(err) => {
+                debug_print!("GetRecentContacts: err {err:?}");
+                RecentContacts {
+                    id: message.id,
+                    error: err.to_string(),
+                    ..Default::default()
+                }
+                .send_signal_to_dart();
+            }
+        }
+    }
+}
+
+pub async fn communicate(clients: ArcMatrixClients) {
+    tokio::join!(
+        communicate_search(clients.clone()),
+        communicate_profile(clients.clone()),
+        communicate_recent(clients),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachedProfile, PROFILE_TTL_MS};
+
+    #[test]
+    fn cached_profile_expires() {
+        let cached = CachedProfile {
+            display_name: None,
+            avatar_url: None,
+            fetched_at: 1_000,
+        };
+        assert!(cached.is_fresh(1_000 + PROFILE_TTL_MS - 1));
+        assert!(!cached.is_fresh(1_000 + PROFILE_TTL_MS));
+    }
+}